@@ -8,6 +8,7 @@
 , pkg-config
 , libiconv
 , openssl
+, sqlite
 , rustfmt
 , cargo
 , rustc
@@ -29,6 +30,7 @@ naersk.lib."${targetPlatform.system}".buildPackage rec {
     cargo
     rustc
     libiconv
+    sqlite
   ];
   checkInputs = [ cargo rustc ];
 
@@ -0,0 +1,211 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::services::plan::{Account, Action};
+use crate::UserConfig;
+
+fn default_max_size_mb() -> u64 {
+    10
+}
+
+fn default_retention_days() -> u64 {
+    365
+}
+
+/// Settings of the audit log.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    /// JSONL file every change is appended to.
+    pub path: PathBuf,
+    /// The file is rotated once it grows beyond this size.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Rotated files older than this are deleted.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+impl AuditConfig {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.max_size_mb == 0 {
+            anyhow::bail!("audit.max_size_mb has to be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// A single change written to the audit log.
+#[derive(Serialize, Debug)]
+pub struct AuditEntry<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub service: &'a str,
+    pub username: &'a str,
+    pub action: &'a Action,
+    /// The account before the change, `None` if it did not exist.
+    pub before: Option<&'a Account>,
+    /// The account as it should be after the change, `None` if it is offboarded.
+    pub after: Option<&'a Account>,
+    /// The entry of the user source the change was derived from.
+    pub source: Option<&'a UserConfig>,
+    /// Set if the service rejected the change.
+    pub error: Option<&'a str>,
+}
+
+/// Append-only JSONL log of every change applied to a service.
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> Self {
+        AuditLog {
+            config,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Appends `entry` and syncs it to disk, rotating the file first if it is too large.
+    pub fn record(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        if self.rotation_due()? {
+            *file = None;
+            self.rotate()?;
+        }
+        if file.is_none() {
+            if let Some(parent) = self.config.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.config.path)?,
+            );
+        }
+        let file = file.as_mut().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn rotation_due(&self) -> anyhow::Result<bool> {
+        match fs::metadata(&self.config.path) {
+            Ok(metadata) => Ok(metadata.len() >= self.config.max_size_mb * 1024 * 1024),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Renames the current file to `<path>.<timestamp>`, with a counter appended if
+    /// that name is taken, and deletes rotated files past the retention period.
+    fn rotate(&self) -> anyhow::Result<()> {
+        let path = &self.config.path;
+        let prefix = format!(
+            "{}.",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let name = prefix.clone() + &Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut rotated = path.with_file_name(&name);
+        let mut counter = 1;
+        while rotated.try_exists()? {
+            rotated = path.with_file_name(format!("{name}.{counter}"));
+            counter += 1;
+        }
+        fs::rename(path, &rotated)?;
+        info!("Rotated audit log to {}", rotated.display());
+
+        // Failing to clean up must not lose the entry that triggered the rotation
+        if let Err(e) = self.delete_expired(&prefix) {
+            warn!("Failed to delete expired audit logs: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Deletes the rotated files starting with `prefix` that are past the retention
+    /// period.
+    fn delete_expired(&self, prefix: &str) -> anyhow::Result<()> {
+        let cutoff = SystemTime::now()
+            - Duration::from_secs(self.config.retention_days.saturating_mul(24 * 60 * 60));
+        let dir = match self.config.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(prefix)
+                || entry.metadata()?.modified()? >= cutoff
+            {
+                continue;
+            }
+            match fs::remove_file(entry.path()) {
+                Ok(()) => info!("Deleted expired audit log {}", entry.path().display()),
+                Err(e) => warn!("Failed to delete {}: {}", entry.path().display(), e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotations_within_a_second_keep_every_file() {
+        let dir = std::env::temp_dir().join(format!("audit-{:x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let log = AuditLog::new(AuditConfig {
+            path: path.clone(),
+            max_size_mb: 1,
+            retention_days: 365,
+        });
+        let action = Action::DeleteUser {
+            username: "alice".to_string(),
+        };
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            service: "test",
+            username: "alice",
+            action: &action,
+            before: None,
+            after: None,
+            source: None,
+            error: None,
+        };
+
+        for _ in 0..3 {
+            *log.file.lock().unwrap() = None;
+            fs::write(&path, vec![b' '; 1024 * 1024]).unwrap();
+            log.record(&entry).unwrap();
+        }
+
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, 4);
+    }
+
+    #[test]
+    fn rejects_zero_max_size() {
+        let config = AuditConfig {
+            path: PathBuf::from("audit.jsonl"),
+            max_size_mb: 0,
+            retention_days: 365,
+        };
+        assert!(config.check().is_err());
+    }
+}
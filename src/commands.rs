@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{Days, Local};
+use log::{error, info};
+
+use crate::format::Format;
+use crate::history::{History, HistoryFilter};
+use crate::services::Service;
+use crate::{load_sources, load_user_configs, load_users, Config, UserConfig};
+
+/// Prints the resolved users as a table.
+pub async fn list_users(config: &Config) -> anyhow::Result<()> {
+    let users = sorted(load_user_configs(config).await?);
+    let rows = users
+        .iter()
+        .map(|(username, user)| {
+            [
+                username.to_string(),
+                [user.first_name.as_deref(), user.last_name.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                user.email.clone().unwrap_or_default(),
+                if user.enabled { "yes" } else { "no" }.to_string(),
+                user.role_names().collect::<Vec<_>>().join(", "),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let header = ["USERNAME", "NAME", "EMAIL", "ENABLED", "ROLES"].map(str::to_string);
+    let mut widths = header.clone().map(|column| column.chars().count());
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{column:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// Prints the resolved configuration of `username` and its account in every service.
+pub async fn show(config: &Config, username: &str) -> anyhow::Result<()> {
+    let users = load_user_configs(config).await?;
+    let user = users.get(username);
+    match user {
+        Some(user) => println!("{username}: {}", serde_json::to_string(user)?),
+        None => println!("{username}: not in the user source"),
+    }
+
+    for keycloak_config in &config.keycloak.0 {
+        show_service(keycloak_config, username, user).await;
+    }
+    for authentik_config in &config.authentik.0 {
+        show_service(authentik_config, username, user).await;
+    }
+    for gitlab_config in &config.gitlab.0 {
+        show_service(gitlab_config, username, user).await;
+    }
+    Ok(())
+}
+
+async fn show_service<S: Service>(service: &S, username: &str, user: Option<&UserConfig>) {
+    let name = service.name();
+    match service.inspect(username, user).await {
+        Ok(inspection) => {
+            match &inspection.current {
+                Some(account) => println!("[{name}] current: {account}"),
+                None => println!("[{name}] current: no account"),
+            }
+            match &inspection.desired {
+                Some(account) => println!("[{name}] desired: {account}"),
+                None => println!("[{name}] desired: no account"),
+            }
+            if inspection.protected {
+                println!("[{name}] protected, never changed by the tool");
+            }
+        }
+        Err(e) => println!("[{name}] error: {e:#}"),
+    }
+}
+
+/// Checks the user source for problems without contacting any service.
+pub async fn validate(config: &Config) -> anyhow::Result<()> {
+    let (users, conflicts) = load_users(config).await?;
+    let users = sorted(users);
+    let mut errors = 0;
+    let mut warnings = 0;
+    for conflict in &conflicts {
+        println!("error: {conflict}");
+        errors += 1;
+    }
+    if users.is_empty() {
+        println!("error: the user source returned no users");
+        errors += 1;
+    }
+
+    let mut emails = HashMap::<String, &str>::new();
+    for (username, user) in &users {
+        if let Some(email) = &user.email {
+            if !email.contains('@') {
+                println!("error: {username}: invalid email {email}");
+                errors += 1;
+            }
+            if let Some(other) = emails.insert(email.to_lowercase(), username) {
+                println!("error: {username}: email {email} is also used by {other}");
+                errors += 1;
+            }
+        }
+        if let Some(matrix_id) = &user.matrix_id {
+            if !matrix_id.starts_with('@') || !matrix_id.contains(':') {
+                println!("error: {username}: invalid matrix id {matrix_id}");
+                errors += 1;
+            }
+        }
+        if user.roles.is_empty() {
+            println!("warning: {username}: no roles");
+            warnings += 1;
+        }
+    }
+
+    if errors > 0 {
+        anyhow::bail!("{errors} errors and {warnings} warnings");
+    }
+    info!(
+        "Configuration and {} users are valid, {} warnings",
+        users.len(),
+        warnings
+    );
+    Ok(())
+}
+
+/// Prints the merged users in `format`, usable as a `file` user source. Unlike the other
+/// commands it keeps every term and leaves implied roles out, so no data is lost when
+/// converting a user file.
+pub async fn export(config: &Config, format: Format) -> anyhow::Result<()> {
+    let (users, conflicts) = load_sources(config).await?;
+    for conflict in conflicts {
+        error!("Conflicting user sources: {conflict}");
+    }
+    let users = sorted(users);
+    println!("{}", format.to_string_pretty(&users)?);
+    Ok(())
+}
+
+/// Prints the roles whose term ends within `weeks` weeks, soonest first.
+pub async fn terms(config: &Config, weeks: u32) -> anyhow::Result<()> {
+    let until = Local::now().date_naive() + Days::new(7 * u64::from(weeks));
+    let users = load_user_configs(config).await?;
+    let mut expiring = users
+        .iter()
+        .flat_map(|(username, user)| {
+            user.roles.iter().filter_map(move |assignment| {
+                let end = assignment.valid_until.filter(|end| *end <= until)?;
+                Some((end, username, &assignment.role))
+            })
+        })
+        .collect::<Vec<_>>();
+    expiring.sort();
+    if expiring.is_empty() {
+        info!("No terms end in the next {weeks} weeks");
+    }
+    for (end, username, role) in expiring {
+        println!("{end}  {username}  {role}");
+    }
+    Ok(())
+}
+
+/// Prints everything the tool did to `username`, oldest first.
+pub fn history(
+    history: Option<&History>,
+    username: &str,
+    filter: &HistoryFilter,
+) -> anyhow::Result<()> {
+    let Some(history) = history else {
+        anyhow::bail!("no history configured");
+    };
+    for entry in history.timeline(username, filter)? {
+        println!(
+            "{} [{}] {}",
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            entry.service,
+            entry.action
+        );
+    }
+    Ok(())
+}
+
+fn sorted(users: HashMap<String, UserConfig>) -> BTreeMap<String, UserConfig> {
+    users.into_iter().collect()
+}
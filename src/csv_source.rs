@@ -0,0 +1,242 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use schemars::JsonSchema;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::role_assignment::RoleAssignment;
+use crate::UserConfig;
+
+/// A CSV file with one user per row and a header row, e.g. an export of the Wahlamt.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CsvConfig {
+    path: PathBuf,
+    /// Separator between the fields of a row.
+    #[serde(default = "default_delimiter")]
+    delimiter: char,
+    /// Separator between the roles in the roles column, must not be empty.
+    #[serde(
+        default = "default_role_separator",
+        deserialize_with = "deserialize_separator"
+    )]
+    role_separator: String,
+    #[serde(default)]
+    columns: CsvColumns,
+}
+
+/// Header of the column holding each field. A column left out defaults to the name of
+/// the field and may be missing from the file; a column given here has to exist.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct CsvColumns {
+    username: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    email: Option<String>,
+    matrix_id: Option<String>,
+    roles: Option<String>,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_role_separator() -> String {
+    ";".to_string()
+}
+
+fn deserialize_separator<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let separator = String::deserialize(deserializer)?;
+    if separator.is_empty() {
+        return Err(D::Error::custom("the role separator must not be empty"));
+    }
+    Ok(separator)
+}
+
+impl CsvConfig {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Reads the users from the CSV file. Rows with the same username are merged, the
+/// roles of all of them are kept.
+pub fn get_user_configs(config: &CsvConfig) -> anyhow::Result<HashMap<String, UserConfig>> {
+    let delimiter = u8::try_from(config.delimiter)
+        .ok()
+        .filter(u8::is_ascii)
+        .context("the CSV delimiter has to be an ASCII character")?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(csv::Trim::All)
+        .from_path(&config.path)
+        .with_context(|| format!("failed to read {}", config.path.display()))?;
+
+    let headers = reader.headers()?.clone();
+    let column = |configured: &Option<String>, field: &str| -> anyhow::Result<Option<usize>> {
+        let index = headers
+            .iter()
+            .position(|header| header == configured.as_deref().unwrap_or(field));
+        match (index, configured) {
+            (None, Some(header)) => anyhow::bail!("the CSV file has no column {header}"),
+            (index, _) => Ok(index),
+        }
+    };
+    let columns = &config.columns;
+    let username =
+        column(&columns.username, "username")?.context("the CSV file has no column username")?;
+    let first_name = column(&columns.first_name, "first_name")?;
+    let last_name = column(&columns.last_name, "last_name")?;
+    let email = column(&columns.email, "email")?;
+    let matrix_id = column(&columns.matrix_id, "matrix_id")?;
+    let roles = column(&columns.roles, "roles")?;
+
+    let mut users = HashMap::<String, UserConfig>::new();
+    for record in reader.records() {
+        let record = record.with_context(|| format!("invalid {}", config.path.display()))?;
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let Some(name) = field(Some(username)) else {
+            anyhow::bail!(
+                "line {} of {} has no username",
+                record.position().map_or(0, |position| position.line()),
+                config.path.display()
+            );
+        };
+        let mut user_roles = field(roles)
+            .map(|roles| {
+                roles
+                    .split(config.role_separator.as_str())
+                    .map(str::trim)
+                    .filter(|role| !role.is_empty())
+                    .map(|role| RoleAssignment::from(role.to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        match users.entry(name) {
+            Entry::Occupied(mut entry) => entry.get_mut().roles.append(&mut user_roles),
+            Entry::Vacant(entry) => {
+                entry.insert(UserConfig {
+                    first_name: field(first_name),
+                    last_name: field(last_name),
+                    email: field(email),
+                    matrix_id: field(matrix_id),
+                    roles: user_roles,
+                    enabled: true,
+                });
+            }
+        }
+    }
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Reads `csv` with the source configured by `config`, without its `path`.
+    fn load(
+        csv: &str,
+        mut config: serde_json::Value,
+    ) -> anyhow::Result<HashMap<String, UserConfig>> {
+        let path = std::env::temp_dir().join(format!("users-{:x}.csv", rand::random::<u64>()));
+        std::fs::write(&path, csv).unwrap();
+        config["path"] = json!(path);
+        let users = get_user_configs(&serde_json::from_value(config).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        users
+    }
+
+    fn roles(user: &UserConfig) -> Vec<&str> {
+        user.role_names().collect()
+    }
+
+    #[test]
+    fn reads_the_default_columns() {
+        let users = load(
+            "username,first_name,last_name,email,roles\nalice,Alice,Liddell,alice@hhu.de,Rat;Kasse\n",
+            json!({}),
+        )
+        .unwrap();
+        let alice = &users["alice"];
+        assert_eq!(alice.first_name.as_deref(), Some("Alice"));
+        assert_eq!(alice.last_name.as_deref(), Some("Liddell"));
+        assert_eq!(alice.email.as_deref(), Some("alice@hhu.de"));
+        assert_eq!(alice.matrix_id, None);
+        assert_eq!(roles(alice), vec!["Rat", "Kasse"]);
+    }
+
+    #[test]
+    fn maps_configured_headers() {
+        let users = load(
+            "Kennung,Vorname,Gremium\nalice,Alice,Rat\n",
+            json!({"columns": {"username": "Kennung", "first_name": "Vorname", "roles": "Gremium"}}),
+        )
+        .unwrap();
+        assert_eq!(users["alice"].first_name.as_deref(), Some("Alice"));
+        assert_eq!(roles(&users["alice"]), vec!["Rat"]);
+    }
+
+    #[test]
+    fn configured_columns_have_to_exist() {
+        let error = load(
+            "username,roles\nalice,Rat\n",
+            json!({"columns": {"email": "Mail"}}),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "the CSV file has no column Mail");
+    }
+
+    #[test]
+    fn unconfigured_columns_may_be_missing() {
+        let users = load("username\nalice\n", json!({})).unwrap();
+        assert_eq!(users["alice"].email, None);
+        assert!(users["alice"].roles.is_empty());
+    }
+
+    #[test]
+    fn uses_the_delimiter_and_role_separator() {
+        let users = load(
+            "username;roles\nalice;Rat | Kasse\n",
+            json!({"delimiter": ";", "role_separator": "|"}),
+        )
+        .unwrap();
+        assert_eq!(roles(&users["alice"]), vec!["Rat", "Kasse"]);
+    }
+
+    #[test]
+    fn rows_of_the_same_user_add_up_their_roles() {
+        let users = load(
+            "username,first_name,roles\nalice,Alice,Rat\nbob,Bob,Kasse\nalice,,Technik\n",
+            json!({}),
+        )
+        .unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["alice"].first_name.as_deref(), Some("Alice"));
+        assert_eq!(roles(&users["alice"]), vec!["Rat", "Technik"]);
+    }
+
+    #[test]
+    fn rejects_rows_without_username() {
+        let error = load("username,roles\nalice,Rat\n,Kasse\n", json!({})).unwrap_err();
+        assert!(error.to_string().contains("line 3"), "{error}");
+    }
+
+    #[test]
+    fn rejects_an_empty_role_separator() {
+        let error =
+            serde_json::from_value::<CsvConfig>(json!({"path": "users.csv", "role_separator": ""}))
+                .unwrap_err();
+        assert!(error.to_string().contains("must not be empty"), "{error}");
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info};
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, RwLock};
+
+use crate::server::{self, RunStatus, Status};
+use crate::services::plan::{RunOptions, Scope};
+use crate::Config;
+
+/// Settings of the `daemon` subcommand, all durations in seconds.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Time between two sync runs.
+    pub interval: u64,
+    /// Up to this much time is randomly added to every wait.
+    pub jitter: u64,
+    /// Wait after the first failed run, doubled after every further failure.
+    pub initial_backoff: u64,
+    /// Upper bound for the wait after failed runs.
+    pub max_backoff: u64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            interval: 3600,
+            jitter: 60,
+            initial_backoff: 60,
+            max_backoff: 3600,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Time to wait before the next run after `failures` failed runs in a row.
+    fn next_delay(&self, failures: u32) -> Duration {
+        let base = if failures == 0 {
+            self.interval
+        } else {
+            self.initial_backoff
+                .saturating_mul(1 << (failures - 1).min(16))
+                .min(self.max_backoff)
+        };
+        Duration::from_secs(base + rand::thread_rng().gen_range(0..=self.jitter))
+    }
+}
+
+/// Syncs all services periodically until the process is interrupted. Every run reloads
+/// the user source and reconnects to the services, a failed run is logged and retried.
+/// If a server is configured, runs can also be triggered over HTTP.
+pub async fn run(config: &Config, options: &RunOptions) -> anyhow::Result<()> {
+    let (triggers, mut requested_runs) = mpsc::channel(8);
+    let status = Arc::new(RwLock::new(Status::default()));
+    if let Some(server_config) = config.server.clone() {
+        let services = config.service_names();
+        let status = status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(&server_config, services, triggers, status).await {
+                error!("HTTP server failed: {:#}", e);
+            }
+        });
+    }
+
+    let mut failures = 0;
+    let mut scope = Scope::default();
+    loop {
+        status.write().await.running = true;
+        let started_at = Utc::now();
+        let options = RunOptions {
+            scope: scope.clone(),
+            ..options.clone()
+        };
+        let result = crate::sync(config, &options).await;
+        match result
+            .as_ref()
+            .map_err(|e| e.to_string())
+            .and_then(|runs| crate::summarize(runs).map_err(|e| e.to_string()))
+        {
+            Ok(()) => {
+                info!("Sync run finished");
+                failures = 0;
+            }
+            Err(e) => {
+                failures += 1;
+                error!("Sync run failed ({} in a row): {}", failures, e);
+            }
+        }
+        *status.write().await = Status {
+            running: false,
+            last_run: Some(RunStatus {
+                started_at,
+                finished_at: Utc::now(),
+                scope,
+                error: result.as_ref().err().map(|e| format!("{e:#}")),
+                runs: result.unwrap_or_default(),
+            }),
+        };
+
+        let delay = config.daemon.next_delay(failures);
+        info!("Next sync run in {}s", delay.as_secs());
+        scope = tokio::select! {
+            _ = tokio::time::sleep(delay) => Scope::default(),
+            Some(requested) = requested_runs.recv() => {
+                info!("Sync run requested: {:?}", requested);
+                requested
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down");
+                return Ok(());
+            }
+        };
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use age::armor::ArmoredReader;
+use age::{Decryptor, IdentityFile};
+use anyhow::Context;
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// File format of the configuration and user files.
+#[derive(Deserialize, Serialize, JsonSchema, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Detects the format from the extension of `path`, falling back to JSON. The `.age`
+    /// extension of encrypted files is skipped.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.strip_suffix(".age").unwrap_or(path);
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+
+    pub fn to_string_pretty<T: Serialize>(self, value: &T) -> anyhow::Result<String> {
+        Ok(match self {
+            Format::Json => serde_json::to_string_pretty(value)?,
+            Format::Toml => toml::to_string_pretty(value)?,
+            Format::Yaml => serde_yaml::to_string(value)?,
+        })
+    }
+}
+
+/// Reads and deserializes the file at `path`, in `format` or the one detected from the
+/// extension. Errors name the offending key, e.g. `keycloak.relm: unknown field`.
+/// Files ending in `.age` are decrypted with the identities in `age_identity` first.
+pub fn read_file<T: DeserializeOwned>(
+    path: &str,
+    format: Option<Format>,
+    age_identity: Option<&Path>,
+) -> anyhow::Result<T> {
+    let text = if path.ends_with(".age") {
+        let identity = age_identity
+            .with_context(|| format!("{path} is encrypted, but no age identity file is given"))?;
+        decrypt(Path::new(path), identity).with_context(|| format!("failed to decrypt {path}"))?
+    } else {
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?
+    };
+    let result = match format.unwrap_or_else(|| Format::from_path(path)) {
+        Format::Json => {
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&text))
+                .map_err(anyhow::Error::from)
+        }
+        Format::Toml => serde_path_to_error::deserialize(toml::Deserializer::new(&text))
+            .map_err(anyhow::Error::from),
+        Format::Yaml => serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(&text))
+            .map_err(anyhow::Error::from),
+    };
+    result.with_context(|| format!("invalid {path}"))
+}
+
+/// Decrypts the age encrypted, optionally armored file at `path`.
+fn decrypt(path: &Path, identity_file: &Path) -> anyhow::Result<String> {
+    let identities = IdentityFile::from_file(identity_file.to_string_lossy().into_owned())
+        .with_context(|| format!("failed to read {}", identity_file.display()))?
+        .into_identities()?;
+    let file = BufReader::new(File::open(path)?);
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(file))?;
+    let mut text = String::new();
+    decryptor
+        .decrypt(identities.iter().map(|identity| identity.as_ref()))?
+        .read_to_string(&mut text)?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[test]
+    fn decrypts_age_files_with_the_identity() {
+        let dir = std::env::temp_dir().join(format!("format-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.join("key.txt");
+        std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        let recipient = identity.to_public();
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .unwrap();
+        let path = dir.join("users.toml.age");
+        let mut writer = encryptor.wrap_output(File::create(&path).unwrap()).unwrap();
+        writer.write_all(b"alice = \"x\"\n").unwrap();
+        writer.finish().unwrap();
+        let path = path.to_str().unwrap();
+
+        let decrypted = read_file::<HashMap<String, String>>(path, None, Some(&identity_file));
+        let missing = read_file::<HashMap<String, String>>(path, None, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(decrypted.unwrap()["alice"], "x");
+        assert!(missing.is_err());
+    }
+}
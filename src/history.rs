@@ -0,0 +1,259 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::{params, Connection};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::services::plan::Action;
+
+/// Settings of the local change history.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// SQLite database the history is kept in, created if missing.
+    pub path: PathBuf,
+}
+
+/// A change the tool applied to an account.
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub service: String,
+    pub action: Action,
+}
+
+/// Restricts the entries returned by [`History::timeline`].
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    pub service: Option<String>,
+    /// First day to include.
+    pub since: Option<NaiveDate>,
+    /// Last day to include.
+    pub until: Option<NaiveDate>,
+    /// Only changes granting, revoking or creating the account with this role, and the
+    /// offboarding of the account, which ends every role it held.
+    pub role: Option<String>,
+}
+
+/// Per-account history of every change applied by the tool.
+#[derive(Debug)]
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    fn init(connection: Connection) -> anyhow::Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS changes (
+                id INTEGER PRIMARY KEY,
+                timestamp TEXT NOT NULL,
+                service TEXT NOT NULL,
+                username TEXT NOT NULL,
+                action TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS changes_username ON changes (username, timestamp);",
+        )?;
+        Ok(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn record(&self, service: &str, action: &Action) -> anyhow::Result<()> {
+        self.record_at(Utc::now(), service, action)
+    }
+
+    fn record_at(
+        &self,
+        timestamp: DateTime<Utc>,
+        service: &str,
+        action: &Action,
+    ) -> anyhow::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO changes (timestamp, service, username, action) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                timestamp,
+                service,
+                action.username(),
+                serde_json::to_string(action)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// All changes to `username` matching `filter`, oldest first.
+    pub fn timeline(
+        &self,
+        username: &str,
+        filter: &HistoryFilter,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        let since = filter
+            .since
+            .map(|day| day.and_hms_opt(0, 0, 0).unwrap().and_utc());
+        let until = filter
+            .until
+            .and_then(|day| day.succ_opt())
+            .map(|day| day.and_hms_opt(0, 0, 0).unwrap().and_utc());
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT timestamp, service, action FROM changes
+            WHERE username = ?1
+                AND (?2 IS NULL OR service = ?2)
+                AND (?3 IS NULL OR timestamp >= ?3)
+                AND (?4 IS NULL OR timestamp < ?4)
+            ORDER BY timestamp, id",
+        )?;
+        let rows = statement.query_map(params![username, filter.service, since, until], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (timestamp, service, action) = row?;
+            let action: Action = serde_json::from_str(&action)?;
+            if filter
+                .role
+                .as_ref()
+                .is_none_or(|role| involves_role(&action, role))
+            {
+                entries.push(HistoryEntry {
+                    timestamp,
+                    service,
+                    action,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn involves_role(action: &Action, role: &str) -> bool {
+    match action {
+        Action::CreateUser { roles, .. } => roles.contains(role),
+        Action::GrantRole { role: r, .. } | Action::RevokeRole { role: r, .. } => r == role,
+        Action::DeleteUser { .. } | Action::DisableUser { .. } => true,
+        Action::UpdateUser { .. } | Action::EnableUser { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    fn grant(role: &str) -> Action {
+        Action::GrantRole {
+            username: "alice".to_string(),
+            role: role.to_string(),
+        }
+    }
+
+    /// A history of alice with a change at the given times.
+    fn history(changes: &[(&str, &str, Action)]) -> History {
+        let history = History::init(Connection::open_in_memory().unwrap()).unwrap();
+        for (timestamp, service, action) in changes {
+            let timestamp = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                .and_utc();
+            history.record_at(timestamp, service, action).unwrap();
+        }
+        history
+    }
+
+    fn services(history: &History, filter: &HistoryFilter) -> Vec<String> {
+        history
+            .timeline("alice", filter)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.service)
+            .collect()
+    }
+
+    fn day(day: &str) -> Option<NaiveDate> {
+        Some(day.parse().unwrap())
+    }
+
+    #[test]
+    fn date_filters_include_both_days() {
+        let history = history(&[
+            ("2024-09-30 23:59:59", "before", grant("A")),
+            ("2024-10-01 00:00:00", "first", grant("A")),
+            ("2024-12-31 23:59:59", "last", grant("A")),
+            ("2025-01-01 00:00:00", "after", grant("A")),
+        ]);
+        let filter = HistoryFilter {
+            since: day("2024-10-01"),
+            until: day("2024-12-31"),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(services(&history, &filter), vec!["first", "last"]);
+        let single_day = HistoryFilter {
+            since: day("2024-12-31"),
+            until: day("2024-12-31"),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(services(&history, &single_day), vec!["last"]);
+    }
+
+    #[test]
+    fn filters_by_service_and_keeps_the_order() {
+        let history = history(&[
+            ("2024-10-02 00:00:00", "gitlab", grant("B")),
+            ("2024-10-01 00:00:00", "keycloak", grant("A")),
+            ("2024-10-03 00:00:00", "keycloak", grant("C")),
+        ]);
+        assert_eq!(
+            services(&history, &HistoryFilter::default()),
+            vec!["keycloak", "gitlab", "keycloak"]
+        );
+        let filter = HistoryFilter {
+            service: Some("keycloak".to_string()),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(services(&history, &filter), vec!["keycloak", "keycloak"]);
+    }
+
+    #[test]
+    fn role_filter_includes_offboarding() {
+        let username = "alice".to_string();
+        let history = history(&[
+            ("2024-10-01 00:00:00", "granted", grant("Rat")),
+            ("2024-10-02 00:00:00", "other", grant("Kasse")),
+            (
+                "2024-10-03 00:00:00",
+                "updated",
+                Action::UpdateUser {
+                    username: username.clone(),
+                    changes: Vec::new(),
+                },
+            ),
+            (
+                "2024-10-04 00:00:00",
+                "disabled",
+                Action::DisableUser {
+                    username: username.clone(),
+                },
+            ),
+            (
+                "2024-10-05 00:00:00",
+                "deleted",
+                Action::DeleteUser { username },
+            ),
+        ]);
+        let filter = HistoryFilter {
+            role: Some("Rat".to_string()),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(
+            services(&history, &filter),
+            vec!["granted", "disabled", "deleted"]
+        );
+    }
+}
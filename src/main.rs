@@ -1,26 +1,62 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
 
+use crate::audit::{AuditConfig, AuditLog};
+use crate::history::{History, HistoryConfig, HistoryFilter};
+use crate::one_or_many::OneOrMany;
+use crate::report::Report;
+use crate::role_assignment::RoleAssignment;
+use crate::role_hierarchy::RoleHierarchy;
+use crate::server::ServerConfig;
 use crate::services::authentik::AuthentikConfig;
 use crate::services::gitlab::GitLabConfig;
 use crate::services::keycloak::KeycloakConfig;
+use crate::services::plan::{RunOptions, SafetyBrake, Scope, ServiceRun};
 use crate::services::Service;
-use clap::Parser;
+use crate::state::{Snapshot, SourceDiff, StateConfig};
+use crate::user_sources::{Conflict, RoleMerge, UserSources};
+use anyhow::Context;
+use chrono::{Local, NaiveDate, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use csv_source::CsvConfig;
+use daemon::DaemonConfig;
+use format::{read_file, Format};
+use futures_util::future::join_all;
+use log::{error, info};
 use nextcloud_table::Nextcloud;
+use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
-use tokio;
 
+mod audit;
+mod commands;
+mod csv_source;
+mod daemon;
+mod format;
+mod history;
+mod metrics;
 mod nextcloud_table;
+mod one_or_many;
+mod pattern;
+mod report;
+mod role_assignment;
+mod role_hierarchy;
+mod role_mapping;
+mod secret;
+mod server;
 mod services;
+mod state;
+mod string_or_map;
+mod user_sources;
 
 fn true_bool() -> bool {
     true
 }
 
-fn false_bool() -> bool {
-    false
-}
-
 #[derive(Parser)]
 #[command(
     version,
@@ -30,37 +66,393 @@ fn false_bool() -> bool {
     color = clap::ColorChoice::Always
 )]
 struct Args {
+    /// Configuration file, required by every subcommand but `schema`
     #[clap(short, long)]
-    config: String,
+    config: Option<String>,
+    /// Format of the configuration file, detected from the extension by default
+    #[clap(long, value_enum)]
+    config_format: Option<Format>,
+    /// age identity file to decrypt a configuration ending in `.age` with
+    #[clap(long, env = "AGE_IDENTITY_FILE")]
+    age_identity: Option<PathBuf>,
+    /// Only print the changes that would be made, without applying them
+    #[clap(long)]
+    dry_run: bool,
+    /// Apply the changes even if they delete or disable more accounts than the safety brake allows
+    #[clap(long)]
+    force: bool,
+    /// Sync the services concurrently
+    #[clap(long)]
+    parallel: bool,
+    /// Write a JSON report of every run to this file, `-` for stdout
+    #[clap(long)]
+    report: Option<String>,
+    /// Only sync these services, e.g. `keycloak,gitlab`
+    #[clap(long, value_delimiter = ',')]
+    only: Vec<String>,
+    /// Do not sync these services
+    #[clap(long, value_delimiter = ',')]
+    skip: Vec<String>,
+    /// Only sync the account of this user; other accounts are neither changed nor removed
+    #[clap(long)]
+    user: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sync all services once (default)
+    Sync,
+    /// Print the changes a sync would make, without applying them
+    Plan,
+    /// Check the configuration and the user source without contacting any service
+    Validate,
+    /// Print the users of the user source as a table
+    #[command(alias = "list")]
+    ListUsers,
+    /// Print the configuration of a user and its account in every service
+    Show { username: String },
+    /// Print the users of the user source, usable as a user file
+    Export {
+        #[clap(long, value_enum, default_value = "json")]
+        format: Format,
+    },
+    /// Print the role terms ending within the next weeks, soonest first
+    Terms {
+        #[clap(long, default_value_t = 4)]
+        weeks: u32,
+    },
+    /// Keep running and sync all services periodically
+    Daemon,
+    /// Print everything the tool did to an account, oldest first
+    History {
+        username: String,
+        /// Only show changes to this service
+        #[clap(long)]
+        service: Option<String>,
+        /// Only show changes on or after this day, e.g. 2024-10-01
+        #[clap(long)]
+        since: Option<NaiveDate>,
+        /// Only show changes on or before this day
+        #[clap(long)]
+        until: Option<NaiveDate>,
+        /// Only show changes granting or revoking this role, and the offboarding of the account
+        #[clap(long)]
+        role: Option<String>,
+    },
+    /// Print the JSON Schema of the configuration or of a user file
+    Schema {
+        #[clap(value_enum, default_value = "config")]
+        kind: SchemaKind,
+    },
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Clone, Copy, ValueEnum)]
+enum SchemaKind {
+    /// The configuration file
+    Config,
+    /// A user file, as read by the `file` user source
+    Users,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
 struct Config {
-    users_provider: UserConfigProvider,
-    keycloak: Option<KeycloakConfig>,
-    authentik: Option<AuthentikConfig>,
-    gitlab: Option<GitLabConfig>,
+    users_provider: UserSources,
+    /// How the roles of a user found in several user sources are combined.
+    #[serde(default)]
+    merge_roles: RoleMerge,
+    /// Roles implying other roles, expanded on every user before any service runs.
+    #[serde(default)]
+    implied_roles: RoleHierarchy,
+    #[serde(default)]
+    keycloak: OneOrMany<KeycloakConfig>,
+    #[serde(default)]
+    authentik: OneOrMany<AuthentikConfig>,
+    #[serde(default)]
+    gitlab: OneOrMany<GitLabConfig>,
+    #[serde(default)]
+    safety_brake: SafetyBrake,
+    #[serde(default)]
+    daemon: DaemonConfig,
+    /// HTTP API of the daemon, disabled if absent.
+    server: Option<ServerConfig>,
+    /// Audit log of all applied changes, disabled if absent.
+    audit: Option<AuditConfig>,
+    /// Per-account change history for the `history` subcommand, disabled if absent.
+    history: Option<HistoryConfig>,
+    /// Snapshot of the last applied user set to diff the source against, disabled if absent.
+    state: Option<StateConfig>,
+    /// age identity file from `--age-identity`, used to decrypt user files ending in `.age`.
+    #[serde(skip)]
+    age_identity: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum UserConfigProvider {
-    File(String),
-    NextcloudTable { nextcloud: Nextcloud, table_id: u64 },
+impl Config {
+    /// Names of all configured services.
+    fn service_names(&self) -> Vec<String> {
+        let keycloak = self.keycloak.0.iter().map(|service| service.name());
+        let authentik = self.authentik.0.iter().map(|service| service.name());
+        let gitlab = self.gitlab.0.iter().map(|service| service.name());
+        keycloak
+            .chain(authentik)
+            .chain(gitlab)
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Fails if `scope` names a service that is not configured.
+    fn check_scope(&self, scope: &Scope) -> anyhow::Result<()> {
+        let names = self.service_names();
+        for service in scope.services.iter().chain(&scope.skip) {
+            if !names.contains(service) {
+                anyhow::bail!(
+                    "unknown service {service}, configured are: {}",
+                    names.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks what the types cannot express, i.e. that service names are unique.
+    fn check(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for name in self.service_names() {
+            if !names.insert(name.clone()) {
+                anyhow::bail!("there are several services named {name}, set a unique `name`");
+            }
+        }
+        if let Some(audit) = &self.audit {
+            audit.check()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum UserConfigProvider {
+    /// File mapping usernames to their configuration.
+    File {
+        path: String,
+        /// Detected from the extension if absent.
+        format: Option<Format>,
+    },
+    NextcloudTable {
+        nextcloud: Nextcloud,
+        table_id: u64,
+    },
+    Csv(CsvConfig),
 }
 
 #[skip_serializing_none]
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct UserConfig {
     first_name: Option<String>,
     last_name: Option<String>,
     email: Option<String>,
     matrix_id: Option<String>,
-    roles: Vec<String>,
+    roles: Vec<RoleAssignment>,
     #[serde(default = "true_bool")]
     enabled: bool,
 }
 
+impl UserConfig {
+    fn role_names(&self) -> impl Iterator<Item = &str> {
+        self.roles.iter().map(|assignment| assignment.role.as_str())
+    }
+}
+
+impl UserConfigProvider {
+    /// Name of the source in conflict reports.
+    fn name(&self) -> String {
+        match self {
+            UserConfigProvider::File { path, .. } => format!("file {path}"),
+            UserConfigProvider::NextcloudTable { table_id, .. } => {
+                format!("nextcloud table {table_id}")
+            }
+            UserConfigProvider::Csv(csv) => format!("csv {}", csv.path().display()),
+        }
+    }
+
+    async fn load(
+        &self,
+        age_identity: Option<&Path>,
+    ) -> anyhow::Result<HashMap<String, UserConfig>> {
+        Ok(match self {
+            UserConfigProvider::NextcloudTable {
+                nextcloud,
+                table_id,
+            } => nextcloud_table::get_user_configs(nextcloud, *table_id).await?,
+            UserConfigProvider::File { path, format } => read_file(path, *format, age_identity)?,
+            UserConfigProvider::Csv(csv) => csv_source::get_user_configs(csv)?,
+        })
+    }
+}
+
+/// Loads the users from the user source with the roles they hold today, implied roles
+/// included. Conflicts between several sources are logged.
+async fn load_user_configs(config: &Config) -> anyhow::Result<HashMap<String, UserConfig>> {
+    let (users, conflicts) = load_users(config).await?;
+    for conflict in conflicts {
+        error!("Conflicting user sources: {conflict}");
+    }
+    Ok(users)
+}
+
+/// Loads and merges all user sources with the roles they hold today, implied roles
+/// included, returning the users and the conflicts between the sources.
+async fn load_users(
+    config: &Config,
+) -> anyhow::Result<(HashMap<String, UserConfig>, Vec<Conflict>)> {
+    let (mut users, conflicts) = load_sources(config).await?;
+    let today = Local::now().date_naive();
+    for user in users.values_mut() {
+        user.roles.retain(|assignment| assignment.is_active(today));
+        user.roles = config.implied_roles.expand(&user.roles);
+    }
+    Ok((users, conflicts))
+}
+
+/// Loads and merges all user sources as they are, with every term and without implied
+/// roles, returning the users and the conflicts between the sources.
+async fn load_sources(
+    config: &Config,
+) -> anyhow::Result<(HashMap<String, UserConfig>, Vec<Conflict>)> {
+    let mut sources = Vec::new();
+    for provider in &config.users_provider.0 {
+        let users = provider
+            .load(config.age_identity.as_deref())
+            .await
+            .with_context(|| format!("failed to load the users from {}", provider.name()))?;
+        sources.push((provider.name(), users));
+    }
+    Ok(user_sources::merge(sources, config.merge_roles))
+}
+
+/// Syncs every configured service in scope once and writes the report of the run.
+async fn sync(config: &Config, options: &RunOptions) -> anyhow::Result<Vec<ServiceRun>> {
+    let started_at = Utc::now();
+    let result = sync_services(config, options).await;
+    if let Some(path) = &options.report {
+        if let Err(e) = Report::new(started_at, options, &result).write(path) {
+            error!("Failed to write the report to {}: {:#}", path, e);
+        }
+    }
+    result
+}
+
+/// Loads the users and syncs every configured service in scope. A failing service does
+/// not affect the others, only a failing user source fails the whole run.
+async fn sync_services(config: &Config, options: &RunOptions) -> anyhow::Result<Vec<ServiceRun>> {
+    let user_configs = load_user_configs(config).await?;
+
+    // An empty source is almost certainly broken and would offboard everyone
+    if user_configs.is_empty() {
+        anyhow::bail!("the user source returned no users, refusing to sync");
+    }
+
+    if let Some(state) = &config.state {
+        if let Some(snapshot) = Snapshot::load(&state.path)? {
+            let diff = SourceDiff::compute(&snapshot.users, &user_configs);
+            if diff.is_empty() {
+                info!("source: unchanged since {}", snapshot.saved_at);
+            } else {
+                options.print(&diff);
+            }
+            if !options.dry_run && !options.force {
+                diff.check_safety_brake(
+                    &options.safety_brake,
+                    &snapshot.users,
+                    &user_configs,
+                    Local::now().date_naive(),
+                )?;
+            }
+        }
+    }
+
+    let scope = &options.scope;
+    let mut services: Vec<Pin<Box<dyn Future<Output = ServiceRun> + '_>>> = Vec::new();
+    for keycloak_config in &config.keycloak.0 {
+        if scope.includes_service(keycloak_config.name()) {
+            services.push(Box::pin(keycloak_config.configure(&user_configs, options)));
+        }
+    }
+    for authentik_config in &config.authentik.0 {
+        if scope.includes_service(authentik_config.name()) {
+            services.push(Box::pin(authentik_config.configure(&user_configs, options)));
+        }
+    }
+    for gitlab_config in &config.gitlab.0 {
+        if scope.includes_service(gitlab_config.name()) {
+            services.push(Box::pin(gitlab_config.configure(&user_configs, options)));
+        }
+    }
+
+    let runs = if options.parallel {
+        join_all(services).await
+    } else {
+        let mut runs = Vec::new();
+        for service in services {
+            runs.push(service.await);
+        }
+        runs
+    };
+
+    // Only a complete, error free run is a reliable base for the next diff
+    if let Some(state) = &config.state {
+        if !options.dry_run && scope.is_everything() && runs.iter().all(ServiceRun::is_success) {
+            let snapshot = Snapshot {
+                saved_at: Utc::now(),
+                users: Cow::Borrowed(&user_configs),
+            };
+            if let Err(e) = snapshot.save(&state.path) {
+                error!(
+                    "Failed to save the snapshot to {}: {:#}",
+                    state.path.display(),
+                    e
+                );
+            }
+        }
+    }
+    Ok(runs)
+}
+
+/// Logs everything that failed in `runs` and returns an error if anything did.
+fn summarize(runs: &[ServiceRun]) -> anyhow::Result<()> {
+    let failed = runs
+        .iter()
+        .filter(|run| !run.is_success())
+        .collect::<Vec<_>>();
+    for run in &failed {
+        if let Some(e) = &run.error {
+            error!("{}: not synced: {}", run.service, e);
+        }
+        for failed_action in &run.failed {
+            error!(
+                "{}: {} failed: {}",
+                run.service, failed_action.action, failed_action.error
+            );
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} of {} services failed: {}",
+            failed.len(),
+            runs.len(),
+            failed
+                .iter()
+                .map(|run| run.service.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     //Set Log Level to Info
@@ -69,28 +461,82 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let args: Args = Args::parse();
-    let config = std::fs::read_to_string(args.config)?;
-    let config: Config = serde_json::from_str(&config)?;
-
-    let user_configs: HashMap<String, UserConfig> = match config.users_provider {
-        UserConfigProvider::NextcloudTable {
-            nextcloud,
-            table_id,
-        } => nextcloud_table::get_user_configs(&nextcloud, table_id).await?,
-        UserConfigProvider::File(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+    if let Some(Command::Schema { kind }) = args.command {
+        let schema = match kind {
+            SchemaKind::Config => schema_for!(Config),
+            SchemaKind::Users => schema_for!(HashMap<String, UserConfig>),
+        };
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+    let Some(config) = &args.config else {
+        anyhow::bail!("--config is required");
     };
+    let mut config: Config = read_file(config, args.config_format, args.age_identity.as_deref())?;
+    config.age_identity = args.age_identity;
+    config.check()?;
 
-    if let Some(keycloak_config) = &config.keycloak {
-        keycloak_config.configure(&user_configs).await?;
-    }
+    let options = RunOptions {
+        dry_run: args.dry_run,
+        force: args.force,
+        safety_brake: config.safety_brake,
+        parallel: args.parallel,
+        scope: Scope {
+            services: args.only,
+            skip: args.skip,
+            user: args.user,
+        },
+        report: args.report,
+        audit: config
+            .audit
+            .clone()
+            .map(|audit| Arc::new(AuditLog::new(audit))),
+        history: match &config.history {
+            Some(history) => Some(Arc::new(History::open(&history.path)?)),
+            None => None,
+        },
+    };
 
-    if let Some(authentik_config) = &config.authentik {
-        authentik_config.configure(&user_configs).await?;
+    config.check_scope(&options.scope)?;
+    if let Some(path) = &options.report {
+        report::check_path(path)?;
     }
 
-    if let Some(gitlab_config) = &config.gitlab {
-        gitlab_config.configure(&user_configs).await?;
+    match args.command.unwrap_or(Command::Sync) {
+        Command::Sync => summarize(&sync(&config, &options).await?),
+        Command::Plan => {
+            let options = RunOptions {
+                dry_run: true,
+                ..options
+            };
+            summarize(&sync(&config, &options).await?)
+        }
+        Command::Validate => commands::validate(&config).await,
+        Command::ListUsers => commands::list_users(&config).await,
+        Command::Show { username } => commands::show(&config, &username).await,
+        Command::Export { format } => commands::export(&config, format).await,
+        Command::Terms { weeks } => commands::terms(&config, weeks).await,
+        Command::Daemon => {
+            if !options.scope.is_everything() {
+                anyhow::bail!("--only, --skip and --user cannot be used with the daemon");
+            }
+            daemon::run(&config, &options).await
+        }
+        Command::Schema { .. } => unreachable!("handled before loading the configuration"),
+        Command::History {
+            username,
+            service,
+            since,
+            until,
+            role,
+        } => {
+            let filter = HistoryFilter {
+                service,
+                since,
+                until,
+                role,
+            };
+            commands::history(options.history.as_deref(), &username, &filter)
+        }
     }
-
-    Ok(())
 }
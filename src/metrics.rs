@@ -0,0 +1,83 @@
+use std::sync::LazyLock;
+
+use chrono::Utc;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, Encoder, GaugeVec,
+    HistogramTimer, HistogramVec, IntCounterVec, TextEncoder,
+};
+
+use crate::services::plan::Action;
+
+static ACTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "usersync_actions_total",
+        "Applied changes by service and kind, e.g. create_user or grant_role",
+        &["service", "action"]
+    )
+    .unwrap()
+});
+
+static RUN_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "usersync_run_duration_seconds",
+        "Duration of syncing a single service",
+        &["service"],
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0]
+    )
+    .unwrap()
+});
+
+static BACKEND_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "usersync_backend_errors_total",
+        "Failed requests to a service while connecting, fetching or applying changes",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static LAST_SUCCESS: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "usersync_last_success_timestamp_seconds",
+        "Unix time of the last successful sync of a service",
+        &["service"]
+    )
+    .unwrap()
+});
+
+pub fn action_applied(service: &str, action: &Action) {
+    let kind = match action {
+        Action::CreateUser { .. } => "create_user",
+        Action::UpdateUser { .. } => "update_user",
+        Action::GrantRole { .. } => "grant_role",
+        Action::RevokeRole { .. } => "revoke_role",
+        Action::EnableUser { .. } => "enable_user",
+        Action::DisableUser { .. } => "disable_user",
+        Action::DeleteUser { .. } => "delete_user",
+    };
+    ACTIONS.with_label_values(&[service, kind]).inc();
+}
+
+/// Starts measuring a sync of `service`, the duration is recorded when the timer drops.
+pub fn run_timer(service: &str) -> HistogramTimer {
+    RUN_DURATION.with_label_values(&[service]).start_timer()
+}
+
+pub fn backend_error(service: &str) {
+    BACKEND_ERRORS.with_label_values(&[service]).inc();
+}
+
+pub fn run_succeeded(service: &str) {
+    LAST_SUCCESS
+        .with_label_values(&[service])
+        .set(Utc::now().timestamp() as f64);
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
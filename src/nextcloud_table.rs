@@ -1,8 +1,13 @@
 use std::collections::HashMap;
 
+use chrono::NaiveDate;
+use log::error;
 use reqwest::Client;
 
+use crate::role_assignment::RoleAssignment;
+use crate::secret::Secret;
 use crate::UserConfig;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -28,29 +33,30 @@ enum ColumnScheme {
         id: u64,
         title: String,
     },
+    /// Dates are sent as text, e.g. `2024-10-01`.
+    Datetime {
+        id: u64,
+        title: String,
+    },
     Selection {
         id: u64,
         title: String,
         subtype: SelectionType,
-        selectionOptions: Vec<SelectionOptions>,
+        #[serde(rename = "selectionOptions")]
+        selection_options: Vec<SelectionOptions>,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(rename_all = "snake_case")]
 enum SelectionType {
     #[serde(rename = "")]
+    #[default]
     Single,
     Multi,
     Check,
 }
 
-impl Default for SelectionType {
-    fn default() -> Self {
-        SelectionType::Single
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 struct SelectionOptions {
     id: u64,
@@ -104,33 +110,24 @@ fn parse_nextcloud_table(
                 .filter_map(|c| {
                     let column = scheme.data.columns.iter().find(|cs| match cs {
                         ColumnScheme::Text { id, .. } => *id == c.column_id(),
+                        ColumnScheme::Datetime { id, .. } => *id == c.column_id(),
                         ColumnScheme::Selection { id, .. } => *id == c.column_id(),
                     })?;
 
                     match (column, c) {
-                        (ColumnScheme::Text { title, .. }, ColumnData::Text { value, .. }) => {
+                        (ColumnScheme::Text { title, .. }, ColumnData::Text { value, .. })
+                        | (ColumnScheme::Datetime { title, .. }, ColumnData::Text { value, .. }) => {
                             Some((title.clone(), NextcloudTableCell::String(value)))
                         }
-                        (
-                            ColumnScheme::Selection {
-                                title,
-                                subtype: SelectionType::Check,
-                                ..
-                            },
-                            ColumnData::Text { value, .. },
-                        ) if value == "true" || value == "false" => Some((
-                            title.clone(),
-                            NextcloudTableCell::Bool(if value == "true" { true } else { false }),
-                        )),
                         (
                             ColumnScheme::Selection {
                                 title,
                                 subtype: SelectionType::Single,
-                                selectionOptions,
+                                selection_options,
                                 ..
                             },
                             ColumnData::Number { value, .. },
-                        ) => selectionOptions
+                        ) => selection_options
                             .iter()
                             .find(|o| o.id == value as u64)
                             .map(|s| (title.clone(), NextcloudTableCell::String(s.label.clone()))),
@@ -138,7 +135,7 @@ fn parse_nextcloud_table(
                             ColumnScheme::Selection {
                                 title,
                                 subtype: SelectionType::Multi,
-                                selectionOptions,
+                                selection_options,
                                 ..
                             },
                             ColumnData::List { value, .. },
@@ -148,9 +145,9 @@ fn parse_nextcloud_table(
                                 value
                                     .iter()
                                     .filter_map(|v| {
-                                        selectionOptions
+                                        selection_options
                                             .iter()
-                                            .find(|o| o.id == (*v) as u64)
+                                            .find(|o| o.id == *v)
                                             .map(|s| s.label.clone())
                                     })
                                     .collect::<Vec<_>>(),
@@ -164,10 +161,11 @@ fn parse_nextcloud_table(
         .collect()
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Nextcloud {
     username: String,
-    password: String,
+    password: Secret,
     url: String,
 }
 
@@ -178,13 +176,16 @@ async fn get_nextcloud_table(
     let client = Client::new();
 
     let scheme = client
-        .get(&format!(
+        .get(format!(
             "{}/ocs/v2.php/apps/tables/api/2/tables/scheme/{}",
             nextcloud.url, table_id
         ))
         .header("Accept", "application/json")
         .header("OCS-APIRequest", "true")
-        .basic_auth(nextcloud.username.clone(), Some(nextcloud.password.clone()))
+        .basic_auth(
+            nextcloud.username.clone(),
+            Some(nextcloud.password.expose()),
+        )
         .send()
         .await?
         .json::<OcsResponse>()
@@ -192,13 +193,16 @@ async fn get_nextcloud_table(
         .ocs;
 
     let columns: Vec<Column> = client
-        .get(&format!(
+        .get(format!(
             "{}/index.php/apps/tables/api/1/tables/{}/rows",
             nextcloud.url, table_id
         ))
         .header("Accept", "application/json")
         .header("OCS-APIRequest", "true")
-        .basic_auth(nextcloud.username.clone(), Some(nextcloud.password.clone()))
+        .basic_auth(
+            nextcloud.username.clone(),
+            Some(nextcloud.password.expose()),
+        )
         .send()
         .await?
         .json()
@@ -209,7 +213,6 @@ async fn get_nextcloud_table(
 
 #[derive(Debug)]
 pub enum NextcloudTableCell {
-    Bool(bool),
     String(String),
     List(Vec<String>),
 }
@@ -218,10 +221,23 @@ pub async fn get_user_configs(
     nextcloud: &Nextcloud,
     table_id: u64,
 ) -> anyhow::Result<HashMap<String, UserConfig>> {
-    let a = get_nextcloud_table(&nextcloud, table_id).await?;
+    let a = get_nextcloud_table(nextcloud, table_id).await?;
 
     Ok(a.into_iter()
         .filter_map(|mut b| {
+            // A term that cannot be read must not grant its roles indefinitely
+            let (valid_from, valid_until) =
+                match (date_cell(&b, "Gültig ab"), date_cell(&b, "Gültig bis")) {
+                    (Ok(valid_from), Ok(valid_until)) => (valid_from, valid_until),
+                    (Err(e), _) | (_, Err(e)) => {
+                        let username = match b.get("Funktionskennung") {
+                            Some(NextcloudTableCell::String(s)) => s.as_str(),
+                            _ => "unknown user",
+                        };
+                        error!("Skipping the row of {username} in table {table_id}: {e}");
+                        return None;
+                    }
+                };
             Some((
                 if let Some(NextcloudTableCell::String(s)) = b.get("Funktionskennung") {
                     s.clone()
@@ -262,7 +278,13 @@ pub async fn get_user_configs(
                                 return None;
                             }
                         };
-                        l
+                        l.into_iter()
+                            .map(|role| RoleAssignment {
+                                role,
+                                valid_from,
+                                valid_until,
+                            })
+                            .collect()
                     } else {
                         return None;
                     },
@@ -277,3 +299,55 @@ pub async fn get_user_configs(
             map
         }))
 }
+
+/// The date in the column `title` of a row, `None` if it is empty. Dates are read as
+/// `2025-09-30`, optionally followed by a time, or as `30.09.2025`.
+fn date_cell(
+    row: &HashMap<String, NextcloudTableCell>,
+    title: &str,
+) -> Result<Option<NaiveDate>, String> {
+    let Some(NextcloudTableCell::String(s)) = row.get(title) else {
+        return Ok(None);
+    };
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    s.get(..10)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        .or_else(|| NaiveDate::parse_from_str(s, "%d.%m.%Y").ok())
+        .map(Some)
+        .ok_or_else(|| format!("{title} is not a date: {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(value: &str) -> HashMap<String, NextcloudTableCell> {
+        HashMap::from([(
+            "Gültig bis".to_string(),
+            NextcloudTableCell::String(value.to_string()),
+        )])
+    }
+
+    #[test]
+    fn reads_iso_and_german_dates() {
+        let date = NaiveDate::from_ymd_opt(2025, 9, 30);
+        assert_eq!(date_cell(&row("2025-09-30"), "Gültig bis"), Ok(date));
+        assert_eq!(date_cell(&row("2025-09-30 00:00"), "Gültig bis"), Ok(date));
+        assert_eq!(date_cell(&row("30.09.2025"), "Gültig bis"), Ok(date));
+    }
+
+    #[test]
+    fn empty_or_missing_cells_have_no_date() {
+        assert_eq!(date_cell(&row(""), "Gültig bis"), Ok(None));
+        assert_eq!(date_cell(&HashMap::new(), "Gültig bis"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_values_that_are_no_date() {
+        assert!(date_cell(&row("Ende September"), "Gültig bis").is_err());
+        assert!(date_cell(&row("31.02.2025"), "Gültig bis").is_err());
+    }
+}
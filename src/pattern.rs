@@ -0,0 +1,174 @@
+use regex::{Captures, Regex};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::string_or_map;
+
+/// A name pattern from the configuration. A plain string is a glob (`*` matches any
+/// sequence, `?` a single character), so exact names need no special syntax;
+/// `{"regex": "..."}` is a regular expression that has to match the whole name. Every
+/// wildcard of a glob is a numbered capture group.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "PatternConfig", into = "PatternConfig")]
+pub struct Pattern {
+    source: PatternConfig,
+    regex: Regex,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+enum PatternConfig {
+    Glob(String),
+    Regex { regex: String },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegexConfig {
+    regex: String,
+}
+
+impl<'de> Deserialize<'de> for PatternConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        string_or_map::deserialize(
+            deserializer,
+            "a glob or {\"regex\": ...}",
+            PatternConfig::Glob,
+            |RegexConfig { regex }| PatternConfig::Regex { regex },
+        )
+    }
+}
+
+impl Pattern {
+    pub fn is_match(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+
+    /// The capture groups of `name`, `None` if it does not match.
+    pub fn captures<'a>(&self, name: &'a str) -> Option<Captures<'a>> {
+        self.regex.captures(name)
+    }
+
+    /// Whether `group` is the number or name of a capture group.
+    pub fn has_group(&self, group: &str) -> bool {
+        match group.parse::<usize>() {
+            Ok(index) => index < self.regex.captures_len(),
+            Err(_) => self
+                .regex
+                .capture_names()
+                .flatten()
+                .any(|name| name == group),
+        }
+    }
+}
+
+impl JsonSchema for Pattern {
+    fn schema_name() -> String {
+        "Pattern".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        PatternConfig::json_schema(gen)
+    }
+}
+
+impl TryFrom<PatternConfig> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(source: PatternConfig) -> Result<Self, Self::Error> {
+        let regex = match &source {
+            PatternConfig::Glob(glob) => Regex::new(&glob_to_regex(glob))?,
+            PatternConfig::Regex { regex } => Regex::new(&format!("^(?:{regex})$"))?,
+        };
+        Ok(Pattern { source, regex })
+    }
+}
+
+impl From<Pattern> for PatternConfig {
+    fn from(pattern: Pattern) -> Self {
+        pattern.source
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str("(.*)"),
+            '?' => regex.push_str("(.)"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Whether any of `patterns` matches `name`.
+pub fn matches_any(patterns: &[Pattern], name: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.is_match(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn pattern(config: serde_json::Value) -> Pattern {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn globs_match_special_characters_literally() {
+        let literal = pattern(json!("admin.user+1"));
+        assert!(literal.is_match("admin.user+1"));
+        assert!(!literal.is_match("adminXuser+1"));
+        assert!(!literal.is_match("admin.userr1"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let any = pattern(json!("service-account-*"));
+        assert!(any.is_match("service-account-"));
+        assert!(any.is_match("service-account-gitlab"));
+        assert!(!any.is_match("my-service-account-gitlab"));
+        let single = pattern(json!("bot?"));
+        assert!(single.is_match("bot1"));
+        assert!(!single.is_match("bot"));
+        assert!(!single.is_match("bot12"));
+    }
+
+    #[test]
+    fn regexes_have_to_match_the_whole_name() {
+        let bot = pattern(json!({"regex": "bot"}));
+        assert!(bot.is_match("bot"));
+        assert!(!bot.is_match("robot-1"));
+        // The alternation is anchored as a whole, not just its first and last branch
+        let alternation = pattern(json!({"regex": "bot|admin"}));
+        assert!(alternation.is_match("admin"));
+        assert!(!alternation.is_match("robot"));
+        assert!(!alternation.is_match("admins"));
+    }
+
+    #[test]
+    fn names_unknown_keys() {
+        let error = serde_json::from_value::<Pattern>(json!({"regx": "bot"}))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `regx`"), "{error}");
+    }
+
+    #[test]
+    fn matches_any_of_several_patterns() {
+        let patterns = [
+            pattern(json!("admin")),
+            pattern(json!({"regex": "^bot-[0-9]+$"})),
+        ];
+        assert!(matches_any(&patterns, "admin"));
+        assert!(matches_any(&patterns, "bot-42"));
+        assert!(!matches_any(&patterns, "bot-x"));
+        assert!(!matches_any(&[], "admin"));
+    }
+}
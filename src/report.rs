@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::services::plan::{Action, RunOptions, Scope, ServiceRun};
+
+/// Machine readable summary of a sync run.
+#[derive(Serialize, Debug)]
+pub struct Report<'a> {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub dry_run: bool,
+    pub scope: &'a Scope,
+    /// Error that prevented the run, e.g. an unreachable user source.
+    pub error: Option<String>,
+    pub services: Vec<ServiceReport<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServiceReport<'a> {
+    pub service: &'a str,
+    pub started_at: DateTime<Utc>,
+    pub duration_seconds: f64,
+    /// Error that stopped the service before any change was applied.
+    pub error: Option<&'a str>,
+    /// Every user touched, with the changes made to its account.
+    pub users: BTreeMap<&'a str, Vec<ChangeReport<'a>>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangeReport<'a> {
+    #[serde(flatten)]
+    pub action: &'a Action,
+    pub status: ChangeStatus,
+    pub error: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeStatus {
+    /// Only planned, because this was a dry run.
+    Planned,
+    Applied,
+    Failed,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(
+        started_at: DateTime<Utc>,
+        options: &'a RunOptions,
+        result: &'a anyhow::Result<Vec<ServiceRun>>,
+    ) -> Self {
+        let (error, runs) = match result {
+            Ok(runs) => (None, runs.as_slice()),
+            Err(e) => (Some(format!("{e:#}")), [].as_slice()),
+        };
+        Report {
+            started_at,
+            finished_at: Utc::now(),
+            dry_run: options.dry_run,
+            scope: &options.scope,
+            error,
+            services: runs
+                .iter()
+                .map(|run| ServiceReport::new(run, options.dry_run))
+                .collect(),
+        }
+    }
+
+    /// Writes the report to `path`, or to stdout if it is `-`. The path may contain
+    /// `strftime` placeholders, e.g. `reports/%Y-%m-%dT%H%M%S.json`, to keep one report
+    /// per run.
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        if path == "-" {
+            writeln!(std::io::stdout(), "{json}")?;
+            return Ok(());
+        }
+        check_path(path)?;
+        let path = self.started_at.format(path).to_string();
+        if let Some(parent) = Path::new(&path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, json + "\n")?;
+        Ok(())
+    }
+}
+
+/// Fails if `path` contains a `strftime` placeholder chrono does not know, which would
+/// make formatting it panic.
+pub fn check_path(path: &str) -> anyhow::Result<()> {
+    if StrftimeItems::new(path).any(|item| item == Item::Error) {
+        anyhow::bail!("invalid strftime placeholder in the report path {path}");
+    }
+    Ok(())
+}
+
+impl<'a> ServiceReport<'a> {
+    fn new(run: &'a ServiceRun, dry_run: bool) -> Self {
+        let mut users = BTreeMap::<_, Vec<_>>::new();
+        for action in &run.plan.actions {
+            let error = run
+                .failed
+                .iter()
+                .find(|failed| failed.action == *action)
+                .map(|failed| failed.error.as_str());
+            let status = match error {
+                _ if dry_run => ChangeStatus::Planned,
+                Some(_) => ChangeStatus::Failed,
+                None => ChangeStatus::Applied,
+            };
+            users
+                .entry(action.username())
+                .or_default()
+                .push(ChangeReport {
+                    action,
+                    status,
+                    error,
+                });
+        }
+        ServiceReport {
+            service: &run.service,
+            started_at: run.started_at,
+            duration_seconds: run.duration_seconds,
+            error: run.error.as_deref(),
+            users,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_known_placeholders() {
+        assert!(check_path("reports/%Y-%m-%dT%H%M%S.json").is_ok());
+        assert!(check_path("report.json").is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert!(check_path("reports/%Q.json").is_err());
+    }
+}
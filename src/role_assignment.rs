@@ -0,0 +1,177 @@
+use chrono::NaiveDate;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_with::skip_serializing_none;
+
+use crate::string_or_map;
+
+/// A role of a user. A plain string holds the role indefinitely; `{"role": ...,
+/// "valid_from": "2024-10-01", "valid_until": "2025-09-30"}` only during a term, both
+/// dates inclusive and each optional.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "RoleAssignmentConfig", into = "RoleAssignmentConfig")]
+pub struct RoleAssignment {
+    pub role: String,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+enum RoleAssignmentConfig {
+    Role(String),
+    Term {
+        role: String,
+        valid_from: Option<NaiveDate>,
+        valid_until: Option<NaiveDate>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TermConfig {
+    role: String,
+    valid_from: Option<NaiveDate>,
+    valid_until: Option<NaiveDate>,
+}
+
+impl<'de> Deserialize<'de> for RoleAssignmentConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        string_or_map::deserialize(
+            deserializer,
+            "a role or {\"role\": ..., \"valid_from\": ..., \"valid_until\": ...}",
+            RoleAssignmentConfig::Role,
+            |term: TermConfig| RoleAssignmentConfig::Term {
+                role: term.role,
+                valid_from: term.valid_from,
+                valid_until: term.valid_until,
+            },
+        )
+    }
+}
+
+impl RoleAssignment {
+    /// Whether the role is held on `date`.
+    pub fn is_active(&self, date: NaiveDate) -> bool {
+        self.valid_from.is_none_or(|from| from <= date)
+            && self.valid_until.is_none_or(|until| date <= until)
+    }
+
+    /// Extends the term to also cover `other`'s, e.g. if a role is both held directly and
+    /// implied by another role.
+    pub fn merge(&mut self, other: &RoleAssignment) {
+        self.valid_from = self.valid_from.min(other.valid_from);
+        self.valid_until = self
+            .valid_until
+            .zip(other.valid_until)
+            .map(|(a, b)| a.max(b));
+    }
+}
+
+impl From<String> for RoleAssignment {
+    fn from(role: String) -> Self {
+        RoleAssignment {
+            role,
+            valid_from: None,
+            valid_until: None,
+        }
+    }
+}
+
+impl JsonSchema for RoleAssignment {
+    fn schema_name() -> String {
+        "RoleAssignment".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RoleAssignmentConfig::json_schema(gen)
+    }
+}
+
+impl TryFrom<RoleAssignmentConfig> for RoleAssignment {
+    type Error = String;
+
+    fn try_from(config: RoleAssignmentConfig) -> Result<Self, Self::Error> {
+        Ok(match config {
+            RoleAssignmentConfig::Role(role) => RoleAssignment::from(role),
+            RoleAssignmentConfig::Term {
+                role,
+                valid_from,
+                valid_until,
+            } => {
+                if let (Some(from), Some(until)) = (valid_from, valid_until) {
+                    if until < from {
+                        return Err(format!("the term of {role} ends before it starts"));
+                    }
+                }
+                RoleAssignment {
+                    role,
+                    valid_from,
+                    valid_until,
+                }
+            }
+        })
+    }
+}
+
+impl From<RoleAssignment> for RoleAssignmentConfig {
+    fn from(assignment: RoleAssignment) -> Self {
+        match assignment {
+            RoleAssignment {
+                role,
+                valid_from: None,
+                valid_until: None,
+            } => RoleAssignmentConfig::Role(role),
+            RoleAssignment {
+                role,
+                valid_from,
+                valid_until,
+            } => RoleAssignmentConfig::Term {
+                role,
+                valid_from,
+                valid_until,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn reads_plain_roles_and_terms() {
+        let plain: RoleAssignment = serde_json::from_value(json!("Vorstand")).unwrap();
+        assert_eq!(plain, RoleAssignment::from("Vorstand".to_string()));
+        let term: RoleAssignment =
+            serde_json::from_value(json!({"role": "Vorstand", "valid_until": "2025-09-30"}))
+                .unwrap();
+        assert_eq!(term.valid_until, NaiveDate::from_ymd_opt(2025, 9, 30));
+        assert_eq!(term.valid_from, None);
+    }
+
+    #[test]
+    fn names_unknown_keys() {
+        let error = serde_json::from_value::<RoleAssignment>(
+            json!({"role": "x", "valid_untl": "2020-01-01"}),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("unknown field `valid_untl`"), "{error}");
+    }
+
+    #[test]
+    fn rejects_terms_ending_before_they_start() {
+        assert!(serde_json::from_value::<RoleAssignment>(json!({
+            "role": "x",
+            "valid_from": "2025-10-01",
+            "valid_until": "2025-09-30"
+        }))
+        .is_err());
+    }
+}
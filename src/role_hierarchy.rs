@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::role_assignment::RoleAssignment;
+
+/// Roles implying other roles, e.g. `{"FS_Rat": ["FS_Kooptiert"], "FS_Kooptiert":
+/// ["FS_Dunstkreis"]}`. Implications are transitive and must not form a cycle.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(
+    try_from = "BTreeMap<String, Vec<String>>",
+    into = "BTreeMap<String, Vec<String>>"
+)]
+pub struct RoleHierarchy {
+    implies: BTreeMap<String, Vec<String>>,
+}
+
+impl RoleHierarchy {
+    /// Each of `roles` followed by the roles it implies, directly or transitively,
+    /// without duplicates. Implied roles are held for the term of the role implying them;
+    /// a role reached on several paths is held for the union of their terms.
+    pub fn expand(&self, roles: &[RoleAssignment]) -> Vec<RoleAssignment> {
+        let mut expanded = Vec::<RoleAssignment>::new();
+        let mut pending = roles.iter().rev().cloned().collect::<Vec<_>>();
+        while let Some(assignment) = pending.pop() {
+            let term = match expanded
+                .iter_mut()
+                .find(|existing| existing.role == assignment.role)
+            {
+                Some(existing) => {
+                    let previous = existing.clone();
+                    existing.merge(&assignment);
+                    // Only a widened term has to be passed on to the implied roles
+                    if *existing == previous {
+                        continue;
+                    }
+                    existing.clone()
+                }
+                None => {
+                    expanded.push(assignment.clone());
+                    assignment
+                }
+            };
+            if let Some(implied) = self.implies.get(&term.role) {
+                pending.extend(implied.iter().rev().map(|role| RoleAssignment {
+                    role: role.clone(),
+                    ..term.clone()
+                }));
+            }
+        }
+        expanded
+    }
+
+    /// Fails with the path of the first cycle found, e.g. `A -> B -> A`.
+    fn check_cycles(&self) -> Result<(), String> {
+        let mut done = Vec::new();
+        for role in self.implies.keys() {
+            self.visit(role, &mut Vec::new(), &mut done)?;
+        }
+        Ok(())
+    }
+
+    fn visit<'a>(
+        &'a self,
+        role: &'a String,
+        path: &mut Vec<&'a String>,
+        done: &mut Vec<&'a String>,
+    ) -> Result<(), String> {
+        if let Some(start) = path.iter().position(|other| *other == role) {
+            let cycle = path[start..]
+                .iter()
+                .chain([&role])
+                .map(|role| role.as_str())
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "implied roles form a cycle: {}",
+                cycle.join(" -> ")
+            ));
+        }
+        if done.contains(&role) {
+            return Ok(());
+        }
+        path.push(role);
+        for implied in self.implies.get(role).into_iter().flatten() {
+            self.visit(implied, path, done)?;
+        }
+        path.pop();
+        done.push(role);
+        Ok(())
+    }
+}
+
+impl JsonSchema for RoleHierarchy {
+    fn schema_name() -> String {
+        "RoleHierarchy".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        BTreeMap::<String, Vec<String>>::json_schema(gen)
+    }
+}
+
+impl TryFrom<BTreeMap<String, Vec<String>>> for RoleHierarchy {
+    type Error = String;
+
+    fn try_from(implies: BTreeMap<String, Vec<String>>) -> Result<Self, Self::Error> {
+        let hierarchy = RoleHierarchy { implies };
+        hierarchy.check_cycles()?;
+        Ok(hierarchy)
+    }
+}
+
+impl From<RoleHierarchy> for BTreeMap<String, Vec<String>> {
+    fn from(hierarchy: RoleHierarchy) -> Self {
+        hierarchy.implies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    use super::*;
+
+    fn hierarchy(config: serde_json::Value) -> Result<RoleHierarchy, String> {
+        serde_json::from_value(config).map_err(|e| e.to_string())
+    }
+
+    fn roles(assignments: &[RoleAssignment]) -> Vec<&str> {
+        assignments
+            .iter()
+            .map(|assignment| assignment.role.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn expands_transitively_without_duplicates() {
+        let hierarchy = hierarchy(json!({
+            "FS_Rat": ["FS_Kooptiert"],
+            "FS_Kooptiert": ["FS_Dunstkreis"],
+            "Vorstand": ["FS_Dunstkreis"]
+        }))
+        .unwrap();
+        let expanded = hierarchy.expand(&[
+            RoleAssignment::from("FS_Rat".to_string()),
+            RoleAssignment::from("Vorstand".to_string()),
+        ]);
+        assert_eq!(
+            roles(&expanded),
+            vec!["FS_Rat", "FS_Kooptiert", "FS_Dunstkreis", "Vorstand"]
+        );
+    }
+
+    #[test]
+    fn implied_roles_share_the_term() {
+        let hierarchy = hierarchy(json!({"FS_Rat": ["FS_Kooptiert"]})).unwrap();
+        let until = NaiveDate::from_ymd_opt(2025, 9, 30);
+        let expanded = hierarchy.expand(&[RoleAssignment {
+            role: "FS_Rat".to_string(),
+            valid_from: None,
+            valid_until: until,
+        }]);
+        assert_eq!(expanded[1].role, "FS_Kooptiert");
+        assert_eq!(expanded[1].valid_until, until);
+    }
+
+    #[test]
+    fn longer_terms_reach_roles_implied_by_shorter_ones() {
+        let hierarchy = hierarchy(json!({"Rat": ["Koop"], "Koop": ["Dunst"]})).unwrap();
+        let term = |role: &str, until| RoleAssignment {
+            role: role.to_string(),
+            valid_from: None,
+            valid_until: NaiveDate::from_ymd_opt(until, 1, 1),
+        };
+        let expanded = hierarchy.expand(&[term("Koop", 2026), term("Rat", 2028)]);
+        assert_eq!(
+            expanded,
+            vec![term("Koop", 2028), term("Dunst", 2028), term("Rat", 2028)]
+        );
+
+        // An indefinite role makes everything it implies indefinite
+        let expanded =
+            hierarchy.expand(&[term("Koop", 2026), RoleAssignment::from("Rat".to_string())]);
+        assert!(expanded
+            .iter()
+            .all(|assignment| assignment.valid_until.is_none()));
+    }
+
+    #[test]
+    fn rejects_cycles_with_their_path() {
+        let error = hierarchy(json!({"A": ["B"], "B": ["C"], "C": ["A"]})).unwrap_err();
+        assert!(error.contains("A -> B -> C -> A"), "{error}");
+        assert!(hierarchy(json!({"A": ["A"]})).is_err());
+    }
+
+    #[test]
+    fn accepts_shared_implications() {
+        assert!(hierarchy(json!({"A": ["C"], "B": ["C"], "C": []})).is_ok());
+    }
+}
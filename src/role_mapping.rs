@@ -0,0 +1,205 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::pattern::Pattern;
+
+/// Translates the roles of the user source into the role or group names of a service.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoleMapping {
+    /// Every rule matching a source role adds a role.
+    pub rules: Vec<RoleRule>,
+    /// Drop source roles no rule matches instead of passing them on unchanged.
+    pub drop_unmapped: bool,
+}
+
+/// Maps source roles matching `from` to the role `to`. `to` is a template in which
+/// `{name}` or `{1}` is replaced by the named or numbered capture group of `from`, e.g.
+/// `{"from": {"regex": "FS_(?<fachschaft>.+)_Admin"}, "to": "fs-{fachschaft}-admins"}`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "RoleRuleConfig", into = "RoleRuleConfig")]
+pub struct RoleRule {
+    from: Pattern,
+    to: String,
+    template: Vec<Segment>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RoleRuleConfig {
+    from: Pattern,
+    to: String,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Group(String),
+}
+
+impl RoleMapping {
+    /// The roles of the service for the source `roles`, without duplicates.
+    pub fn map<'a>(&self, roles: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+        let mut mapped = Vec::new();
+        for role in roles {
+            let mut matched = false;
+            for rule in &self.rules {
+                if let Some(role) = rule.apply(role) {
+                    matched = true;
+                    if !mapped.contains(&role) {
+                        mapped.push(role);
+                    }
+                }
+            }
+            if !matched && !self.drop_unmapped && !mapped.iter().any(|other| other == role) {
+                mapped.push(role.to_string());
+            }
+        }
+        mapped
+    }
+}
+
+impl RoleRule {
+    fn apply(&self, role: &str) -> Option<String> {
+        let captures = self.from.captures(role)?;
+        Some(
+            self.template
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(literal) => literal.as_str(),
+                    Segment::Group(group) => match group.parse::<usize>() {
+                        Ok(index) => captures.get(index),
+                        Err(_) => captures.name(group),
+                    }
+                    .map_or("", |capture| capture.as_str()),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl JsonSchema for RoleRule {
+    fn schema_name() -> String {
+        "RoleRule".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RoleRuleConfig::json_schema(gen)
+    }
+}
+
+impl TryFrom<RoleRuleConfig> for RoleRule {
+    type Error = String;
+
+    fn try_from(config: RoleRuleConfig) -> Result<Self, Self::Error> {
+        let mut template = Vec::new();
+        let mut rest = config.to.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed {{ in {}", config.to))?;
+            let group = &rest[start + 1..start + end];
+            if !config.from.has_group(group) {
+                return Err(format!(
+                    "{} uses the unknown capture group {group}",
+                    config.to
+                ));
+            }
+            template.push(Segment::Literal(rest[..start].to_string()));
+            template.push(Segment::Group(group.to_string()));
+            rest = &rest[start + end + 1..];
+        }
+        template.push(Segment::Literal(rest.to_string()));
+        Ok(RoleRule {
+            from: config.from,
+            to: config.to,
+            template,
+        })
+    }
+}
+
+impl From<RoleRule> for RoleRuleConfig {
+    fn from(rule: RoleRule) -> Self {
+        RoleRuleConfig {
+            from: rule.from,
+            to: rule.to,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn mapping(config: serde_json::Value) -> RoleMapping {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn numbers_glob_wildcards_from_left_to_right() {
+        let mapping = mapping(json!({
+            "rules": [{"from": "FS_*_?", "to": "{2}-{1}"}]
+        }));
+        assert_eq!(mapping.map(["FS_Physik_A"]), vec!["A-Physik"]);
+    }
+
+    #[test]
+    fn fills_named_groups_and_literals() {
+        let mapping = mapping(json!({
+            "rules": [{
+                "from": {"regex": "FS_(?<fachschaft>.+)_Admin"},
+                "to": "fs-{fachschaft}-admins"
+            }]
+        }));
+        assert_eq!(
+            mapping.map(["FS_Informatik_Admin"]),
+            vec!["fs-Informatik-admins"]
+        );
+    }
+
+    #[test]
+    fn passes_unmapped_roles_on_unless_dropped() {
+        let rules = json!([{"from": "Vorstand", "to": "admins"}]);
+        let kept = mapping(json!({"rules": rules}));
+        assert_eq!(kept.map(["Vorstand", "Kasse"]), vec!["admins", "Kasse"]);
+        let dropped = mapping(json!({"rules": rules, "drop_unmapped": true}));
+        assert_eq!(dropped.map(["Vorstand", "Kasse"]), vec!["admins"]);
+    }
+
+    #[test]
+    fn adds_every_matching_rule_once() {
+        let mapping = mapping(json!({
+            "rules": [
+                {"from": "Vorstand*", "to": "admins"},
+                {"from": "Vorstand*", "to": "users"},
+                {"from": "Referat*", "to": "users"}
+            ]
+        }));
+        assert_eq!(
+            mapping.map(["Vorstand_Finanzen", "Referat_Kultur"]),
+            vec!["admins", "users"]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_groups() {
+        let error = serde_json::from_value::<RoleRule>(json!({"from": "FS_*", "to": "{2}"}))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown capture group 2"), "{error}");
+        assert!(serde_json::from_value::<RoleRule>(json!({
+            "from": {"regex": "(?<a>.+)"},
+            "to": "{b}"
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_unclosed_braces() {
+        assert!(serde_json::from_value::<RoleRule>(json!({"from": "FS_*", "to": "{1"})).is_err());
+    }
+}
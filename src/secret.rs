@@ -0,0 +1,134 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::string_or_map;
+
+/// A password or token from the configuration. Besides a plain string it can be read
+/// from an environment variable (`{"env": "KC_PASSWORD"}`), a file
+/// (`{"file": "/run/secrets/kc"}`) or a systemd credential
+/// (`{"credential": "kc"}`, read from `$CREDENTIALS_DIRECTORY/kc`).
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(try_from = "SecretConfig", into = "SecretConfig")]
+pub struct Secret {
+    source: SecretConfig,
+    value: String,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+enum SecretConfig {
+    Plain(String),
+    Env { env: String },
+    File { file: PathBuf },
+    Credential { credential: String },
+}
+
+/// The map form of [`SecretConfig`], whose single key names the source.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SecretSource {
+    Env(String),
+    File(PathBuf),
+    Credential(String),
+}
+
+impl<'de> Deserialize<'de> for SecretConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        string_or_map::deserialize(
+            deserializer,
+            "a secret or one of {\"env\": ...}, {\"file\": ...} and {\"credential\": ...}",
+            SecretConfig::Plain,
+            |source| match source {
+                SecretSource::Env(env) => SecretConfig::Env { env },
+                SecretSource::File(file) => SecretConfig::File { file },
+                SecretSource::Credential(credential) => SecretConfig::Credential { credential },
+            },
+        )
+    }
+}
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+}
+
+/// Only shows where the secret comes from, never its value.
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            SecretConfig::Plain(_) => write!(f, "Secret(***)"),
+            source => write!(f, "Secret({source:?})"),
+        }
+    }
+}
+
+impl JsonSchema for Secret {
+    fn schema_name() -> String {
+        "Secret".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SecretConfig::json_schema(gen)
+    }
+}
+
+impl TryFrom<SecretConfig> for Secret {
+    type Error = anyhow::Error;
+
+    fn try_from(source: SecretConfig) -> anyhow::Result<Self> {
+        let value = match &source {
+            SecretConfig::Plain(value) => value.clone(),
+            SecretConfig::Env { env } => std::env::var(env)
+                .map_err(|e| anyhow::anyhow!("failed to read environment variable {env}: {e}"))?,
+            SecretConfig::File { file } => read_secret_file(file)?,
+            SecretConfig::Credential { credential } => {
+                let directory = std::env::var_os("CREDENTIALS_DIRECTORY")
+                    .context("CREDENTIALS_DIRECTORY is not set, is the service run by systemd with LoadCredential=?")?;
+                read_secret_file(&PathBuf::from(directory).join(credential))?
+            }
+        };
+        Ok(Secret { source, value })
+    }
+}
+
+impl From<Secret> for SecretConfig {
+    fn from(secret: Secret) -> Self {
+        secret.source
+    }
+}
+
+/// Reads a secret file, dropping the trailing newline most editors and tools add.
+fn read_secret_file(path: &Path) -> anyhow::Result<String> {
+    let value = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read secret file {}: {e}", path.display()))?;
+    Ok(value.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn reads_plain_secrets() {
+        let secret: Secret = serde_json::from_value(json!("hunter2")).unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{secret:?}"), "Secret(***)");
+    }
+
+    #[test]
+    fn names_unknown_sources() {
+        let error = serde_json::from_value::<Secret>(json!({"evn": "KC_PASSWORD"}))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown variant `evn`"), "{error}");
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use axum::extract::{Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, RwLock};
+
+use crate::metrics;
+use crate::secret::Secret;
+use crate::services::plan::{Scope, ServiceRun};
+
+/// Settings of the HTTP server started by the `daemon` subcommand.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on, e.g. `127.0.0.1:8080`.
+    pub listen: String,
+    /// Bearer token every request has to present.
+    pub token: Secret,
+}
+
+/// Outcome of a finished sync run.
+#[derive(Serialize, Debug, Clone)]
+pub struct RunStatus {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub scope: Scope,
+    /// Error that prevented the run, e.g. an unreachable user source.
+    pub error: Option<String>,
+    pub runs: Vec<ServiceRun>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Status {
+    pub running: bool,
+    pub last_run: Option<RunStatus>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    token: Arc<str>,
+    services: Arc<[String]>,
+    triggers: mpsc::Sender<Scope>,
+    status: Arc<RwLock<Status>>,
+}
+
+/// Serves the trigger API until the process ends. Requested runs are sent to
+/// `triggers`, the daemon keeps `status` up to date.
+pub async fn serve(
+    config: &ServerConfig,
+    services: Vec<String>,
+    triggers: mpsc::Sender<Scope>,
+    status: Arc<RwLock<Status>>,
+) -> anyhow::Result<()> {
+    let state = AppState {
+        token: config.token.expose().into(),
+        services: services.into(),
+        triggers,
+        status,
+    };
+    let app = Router::new()
+        .route("/sync", post(trigger_sync))
+        .route("/status", get(get_status))
+        .route("/webhook/nextcloud", post(nextcloud_webhook))
+        .route("/metrics", get(get_metrics))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(&config.listen).await?;
+    info!("Listening on {}", config.listen);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn authenticate(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), state.token.as_bytes()));
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+/// Compares two byte strings without leaking the position of the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Query of `POST /sync`.
+#[derive(Deserialize, Debug)]
+struct SyncQuery {
+    service: Option<String>,
+    user: Option<String>,
+}
+
+/// `POST /sync`, optionally restricted with `?service=...&user=...`.
+async fn trigger_sync(State(state): State<AppState>, Query(query): Query<SyncQuery>) -> Response {
+    if let Some(service) = &query.service {
+        if !state.services.contains(service) {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("unknown service {service}"),
+            )
+                .into_response();
+        }
+    }
+    let scope = Scope {
+        services: query.service.into_iter().collect(),
+        user: query.user,
+        ..Default::default()
+    };
+    match state.triggers.try_send(scope) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => (StatusCode::TOO_MANY_REQUESTS, "too many queued runs").into_response(),
+    }
+}
+
+/// `GET /status`
+async fn get_status(State(state): State<AppState>) -> Json<Status> {
+    Json(state.status.read().await.clone())
+}
+
+/// `GET /metrics` in the Prometheus text format
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics::render(),
+    )
+}
+
+/// `POST /webhook/nextcloud`, called by Nextcloud when a row of the user table changes.
+async fn nextcloud_webhook(State(state): State<AppState>, Json(event): Json<Value>) -> StatusCode {
+    info!(
+        "Nextcloud webhook: {}",
+        event["event"]["class"].as_str().unwrap_or("unknown event")
+    );
+    // A full run that is already queued covers this change as well
+    if state.triggers.try_send(Scope::default()).is_err() {
+        warn!("Sync queue is full, dropping webhook trigger");
+    }
+    StatusCode::ACCEPTED
+}
@@ -1,26 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::pattern::Pattern;
+use crate::role_mapping::RoleMapping;
+use crate::secret::Secret;
+use crate::services::plan::{Account, Action, OffboardingPolicy};
+use crate::services::{Service, ServiceClient};
 use crate::true_bool;
 use crate::UserConfig;
 use log::*;
 use oauth2::AccessToken;
-use serde_json::json;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
-#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct AuthentikConfig {
+    /// Name of the instance in plans, logs, `--only` and `--skip`, defaults to `authentik`.
+    /// Has to be unique if several instances are configured.
+    pub name: Option<String>,
     pub url: String,
-    pub token: String,
+    pub token: Secret,
+    #[serde(default)]
+    pub on_removed: OffboardingPolicy,
+    #[serde(default)]
+    pub protected_users: Vec<Pattern>,
+    #[serde(default)]
+    pub protected_roles: Vec<Pattern>,
+    /// Translates the source roles into the roles of this instance.
+    #[serde(default)]
+    pub role_mapping: RoleMapping,
 }
 
+/// A page of a paginated Authentik API list.
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
-pub struct AuthentikResponse {
-    pub results: Vec<AuthentikUser>,
+struct AuthentikResponse<T> {
+    pagination: AuthentikPagination,
+    results: Vec<T>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
-pub struct AuthentikResponse2 {
-    pub results: Vec<AuthentikRole>,
+struct AuthentikPagination {
+    /// Number of the next page, `0` on the last page.
+    next: u64,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
@@ -34,10 +56,13 @@ struct AuthentikUser {
     groups_obj: Vec<AuthentikRole>,
 }
 
-struct AuthentikClient {
+pub struct AuthentikClient {
     base_url: String,
     token: AccessToken,
     reqwest_client: reqwest::Client,
+    /// Primary keys of the known users, keyed by username.
+    ids: HashMap<String, i64>,
+    groups: Vec<AuthentikRole>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
@@ -46,216 +71,248 @@ struct AuthentikRole {
     name: String,
 }
 
-impl crate::services::Service for AuthentikConfig {
-    async fn configure(&self, users: &HashMap<String, UserConfig>) -> anyhow::Result<()> {
-        let client = AuthentikClient::new(self.url.clone(), self.token.clone()).await?;
+impl Service for AuthentikConfig {
+    type Client = AuthentikClient;
 
-        let authentik_users = client.get_all_users().await?;
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("authentik")
+    }
 
-        let users_to_create = users
-            .iter()
-            .filter(|user| !authentik_users.iter().any(|k| *user.0 == k.username))
-            .collect::<HashMap<_, _>>();
+    fn on_removed(&self) -> OffboardingPolicy {
+        self.on_removed
+    }
 
-        client.create_users(&users_to_create).await?;
+    fn protected_users(&self) -> &[Pattern] {
+        &self.protected_users
+    }
 
-        let users_to_update = authentik_users
-            .iter()
-            .filter(|authentik_user| users.contains_key(&authentik_user.username))
-            .collect::<Vec<_>>();
+    fn protected_roles(&self) -> &[Pattern] {
+        &self.protected_roles
+    }
+
+    fn role_mapping(&self) -> &RoleMapping {
+        &self.role_mapping
+    }
+
+    async fn connect(&self) -> anyhow::Result<AuthentikClient> {
+        AuthentikClient::new(self.url.clone(), self.token.expose().to_string()).await
+    }
+}
 
-        client.update_users(&users_to_update, &users).await?;
-        client.update_roles(&users_to_update, &users).await?;
+impl ServiceClient for AuthentikClient {
+    async fn fetch_accounts(&mut self) -> anyhow::Result<Vec<Account>> {
+        self.groups = self.get_all("groups").await?;
 
-        let users_to_delete = authentik_users
+        let users: Vec<AuthentikUser> = self.get_all("users").await?;
+        self.ids = users
             .iter()
-            .filter(|authentik_user| !users.contains_key(&authentik_user.username))
-            .collect::<Vec<_>>();
-        client.delete_users(&users_to_delete).await?;
+            .map(|user| (user.username.clone(), user.pk))
+            .collect();
+        Ok(users
+            .into_iter()
+            .map(|user| Account {
+                fields: [("name", user.name), ("email", user.email)]
+                    .into_iter()
+                    .filter_map(|(field, value)| Some((field.to_string(), Value::from(value?))))
+                    .collect(),
+                username: user.username,
+                enabled: user.is_active,
+                roles: user
+                    .groups_obj
+                    .into_iter()
+                    .map(|group| group.name)
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Only groups that already exist in Authentik are assigned.
+    fn desired_account(&self, username: &str, user: &UserConfig) -> Option<Account> {
+        let mut fields = BTreeMap::from([("name".to_string(), Value::from(full_name(user)))]);
+        if let Some(email) = &user.email {
+            fields.insert("email".to_string(), Value::from(email.clone()));
+        }
+        Some(Account {
+            username: username.to_string(),
+            fields,
+            enabled: user.enabled,
+            roles: user
+                .role_names()
+                .filter(|role| self.groups.iter().any(|group| group.name == *role))
+                .map(str::to_string)
+                .collect(),
+        })
+    }
 
+    async fn apply(&mut self, action: &Action) -> anyhow::Result<()> {
+        match action {
+            Action::CreateUser {
+                username,
+                fields,
+                enabled,
+                roles,
+            } => {
+                let mut body = json!({
+                    "username": username,
+                    "is_active": enabled,
+                    "groups": roles
+                        .iter()
+                        .map(|role| self.group(role).map(|group| group.pk))
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                });
+                body.as_object_mut()
+                    .unwrap()
+                    .extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())));
+                let pk = self.create_user(&body).await?;
+                self.ids.insert(username.clone(), pk);
+            }
+            Action::UpdateUser { username, changes } => {
+                let body = changes
+                    .iter()
+                    .map(|change| (change.field.clone(), change.to.clone()))
+                    .collect::<serde_json::Map<_, _>>();
+                self.update_user(self.id(username)?, &Value::Object(body))
+                    .await?;
+            }
+            Action::GrantRole { username, role } => {
+                self.group_membership(self.group(role)?, self.id(username)?, "add_user")
+                    .await?;
+            }
+            Action::RevokeRole { username, role } => {
+                self.group_membership(self.group(role)?, self.id(username)?, "remove_user")
+                    .await?;
+            }
+            Action::EnableUser { username } => {
+                self.update_user(self.id(username)?, &json!({ "is_active": true }))
+                    .await?;
+            }
+            Action::DisableUser { username } => {
+                self.update_user(self.id(username)?, &json!({ "is_active": false }))
+                    .await?;
+            }
+            Action::DeleteUser { username } => {
+                self.delete_user(self.id(username)?).await?;
+            }
+        }
         Ok(())
     }
 }
 
+/// The display name Authentik stores for a user.
+fn full_name(user_config: &UserConfig) -> String {
+    format!(
+        "{} {}",
+        user_config.first_name.as_deref().unwrap_or_default(),
+        user_config.last_name.as_deref().unwrap_or_default()
+    )
+}
+
 impl AuthentikClient {
     async fn new(base_url: String, token_string: String) -> anyhow::Result<Self> {
         let token = AccessToken::new(token_string);
-        // Get a Token with Password Grant
 
         Ok(AuthentikClient {
             base_url,
             token,
             reqwest_client: reqwest::Client::new(),
+            ids: HashMap::new(),
+            groups: Vec::new(),
         })
     }
 
-    async fn create_users(&self, users: &HashMap<&String, &UserConfig>) -> anyhow::Result<()> {
-        for user in users {
-            let user = self
-                .reqwest_client
-                .post(format!("{}/api/v3/core/users/", self.base_url))
-                .bearer_auth(self.token.secret())
-                .json(&json!(
-                    {
-                        "username": user.0,
-                        "name": format!("{} {}", user.1.first_name.clone().unwrap_or("".to_string()), user.1.last_name.clone().unwrap_or("".to_string())),
-                        "email": user.1.email,
-                        "is_active": user.1.enabled,
-                    }
-                ))
-                .send()
-                .await?
-                .text()
-                .await?;
-            info!("Created User: {:?}", user);
-        }
-        Ok(())
-    }
-
-    async fn get_all_users(&self) -> anyhow::Result<Vec<AuthentikUser>> {
-        info!("Getting all users from Authentik");
-        // Create a request
-        Ok(self
-            .reqwest_client
-            .get(format!("{}/api/v3/core/users/", self.base_url))
-            .bearer_auth(self.token.secret())
-            .send()
-            .await?
-            .json::<AuthentikResponse>()
-            .await?
-            .results)
+    fn id(&self, username: &str) -> anyhow::Result<i64> {
+        self.ids
+            .get(username)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("unknown authentik user {username}"))
     }
 
-    async fn disable_users(&self, users: &Vec<&AuthentikUser>) -> anyhow::Result<()> {
-        for user in users {
-            info!("Disabling user: {}", user.username);
-            let _ = self
-                .reqwest_client
-                .put(format!("{}/api/v3/core/users/{}", self.base_url, user.pk))
-                .bearer_auth(self.token.secret())
-                .json(&json!({
-                    "is_active": false
-                }))
-                .send()
-                .await?;
-        }
-        Ok(())
+    fn group(&self, name: &str) -> anyhow::Result<&AuthentikRole> {
+        self.groups
+            .iter()
+            .find(|group| group.name == name)
+            .ok_or_else(|| anyhow::anyhow!("unknown authentik group {name}"))
     }
 
-    async fn delete_users(&self, users: &Vec<&AuthentikUser>) -> anyhow::Result<()> {
-        for user in users {
-            info!("Deleting user: {}", user.username);
-            let _ = self
+    /// Fetches every page of a list endpoint below `/api/v3/core/`.
+    async fn get_all<T: DeserializeOwned>(&self, endpoint: &str) -> anyhow::Result<Vec<T>> {
+        info!("Getting all {} from Authentik", endpoint);
+        let mut results = Vec::new();
+        let mut page = 1;
+        while page != 0 {
+            let response = self
                 .reqwest_client
-                .delete(format!("{}/api/v3//core/users/{}", self.base_url, user.pk))
+                .get(format!("{}/api/v3/core/{}/", self.base_url, endpoint))
+                .query(&[("page", page)])
                 .bearer_auth(self.token.secret())
-                .json(&json!({
-                    "is_active": false
-                }))
                 .send()
+                .await?
+                .error_for_status()?
+                .json::<AuthentikResponse<T>>()
                 .await?;
+            results.extend(response.results);
+            page = response.pagination.next;
         }
-        Ok(())
+        Ok(results)
     }
 
-    async fn get_all_realm_roles(&self) -> anyhow::Result<Vec<AuthentikRole>> {
-        info!("Getting all realm roles from Authentik");
-        Ok(self
+    /// Creates a user and returns its primary key.
+    async fn create_user(&self, body: &Value) -> anyhow::Result<i64> {
+        let user = self
             .reqwest_client
-            .get(format!("{}/api/v3/core/groups/", self.base_url))
+            .post(format!("{}/api/v3/core/users/", self.base_url))
             .bearer_auth(self.token.secret())
+            .json(body)
             .send()
             .await?
-            .json::<AuthentikResponse2>()
-            .await?
-            .results)
-    }
-
-    fn roles_to_add(
-        config_roles: &Vec<String>,
-        authentik_roles: &Vec<AuthentikRole>,
-    ) -> Vec<AuthentikRole> {
-        authentik_roles
-            .iter()
-            .filter(|role| config_roles.contains(&role.name))
-            .cloned()
-            .collect()
-    }
-
-    async fn update_roles(
-        &self,
-        users_authentik: &Vec<&AuthentikUser>,
-        user_configs: &HashMap<String, UserConfig>,
-    ) -> anyhow::Result<()> {
-        info!("Updating roles for users");
-        let existing_roles = self.get_all_realm_roles().await?;
-        for user in users_authentik {
-            let configured_roles = user_configs[&user.username].roles.clone();
-            let roles = Self::roles_to_add(&configured_roles, &existing_roles);
-
-            self.update_user_roles(&format!("{:?}", &user.pk), &roles)
-                .await?;
-        }
-        Ok(())
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        user["pk"]
+            .as_i64()
+            .ok_or_else(|| anyhow::anyhow!("authentik did not return the pk of the new user"))
     }
 
-    async fn update_user_roles(
-        &self,
-        user_id: &String,
-        roles: &Vec<AuthentikRole>,
-    ) -> anyhow::Result<()> {
-        info!("Updating roles for user: {}", user_id);
-        let r = &json!({"groups": roles.iter().map(|role| role.pk).collect::<Vec<Uuid>>()});
-        println!("{}", r);
-        if !roles.is_empty() {
-            match self
-                .reqwest_client
-                .patch(format!("{}/api/v3/core/users/{}/", self.base_url, user_id))
-                .bearer_auth(self.token.secret())
-                .json(&json!({"groups": roles.iter().map(|role| role.pk).collect::<Vec<Uuid>>()}))
-                .send()
-                .await?
-                .status()
-            {
-                reqwest::StatusCode::OK => {
-                    info!("Added roles: {:?} to {:?}", roles, user_id)
-                }
-                status => error!("Failed to add roles to user: {}", status),
-            }
-        }
+    async fn update_user(&self, pk: i64, body: &Value) -> anyhow::Result<()> {
+        self.reqwest_client
+            .patch(format!("{}/api/v3/core/users/{}/", self.base_url, pk))
+            .bearer_auth(self.token.secret())
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 
-    async fn update_users(
-        &self,
-        users: &Vec<&AuthentikUser>,
-        user_configs: &HashMap<String, UserConfig>,
-    ) -> anyhow::Result<()> {
-        for user in users {
-            let user_config = &user_configs[&user.username];
-            self.update_user(&user, &user_config).await?;
-        }
+    async fn delete_user(&self, pk: i64) -> anyhow::Result<()> {
+        self.reqwest_client
+            .delete(format!("{}/api/v3/core/users/{}/", self.base_url, pk))
+            .bearer_auth(self.token.secret())
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 
-    async fn update_user(
+    /// Adds the user to or removes it from a group, `operation` being `add_user` or
+    /// `remove_user`.
+    async fn group_membership(
         &self,
-        user: &AuthentikUser,
-        user_config: &UserConfig,
+        group: &AuthentikRole,
+        user_pk: i64,
+        operation: &str,
     ) -> anyhow::Result<()> {
+        debug!("{} {} in group {}", operation, user_pk, group.name);
         self.reqwest_client
-            .put(format!("{}/api/v3//core/users/{}/", self.base_url, user.pk))
-            .bearer_auth(self.token.secret())
-            .json(&json!(
-                {
-                    "name": format!("{:?} {:?}", user_config.first_name, user_config.last_name),
-                    "email": user_config.email,
-                    "is_active": user_config.enabled,
-                    "username": user.username
-                }
+            .post(format!(
+                "{}/api/v3/core/groups/{}/{}/",
+                self.base_url, group.pk, operation
             ))
+            .bearer_auth(self.token.secret())
+            .json(&json!({ "pk": user_pk }))
             .send()
-            .await?;
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 }
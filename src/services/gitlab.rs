@@ -1,126 +1,259 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
+use crate::pattern::Pattern;
+use crate::role_mapping::RoleMapping;
+use crate::secret::Secret;
 use crate::UserConfig;
 use gitlab::api::common::AccessLevel;
-use gitlab::api::{self, Query};
-use gitlab::Gitlab;
-use log::info;
+use gitlab::api::{self, AsyncQuery};
+use gitlab::{AsyncGitlab, GitlabBuilder};
+use log::{debug, info};
 
-use super::Service;
+use super::plan::{Account, Action, OffboardingPolicy};
+use super::{Service, ServiceClient};
 
-#[derive(serde::Deserialize, serde::Serialize, Debug)]
+/// Access levels the tool assigns, used as the role names of group members.
+const ACCESS_LEVELS: [AccessLevel; 7] = [
+    AccessLevel::Minimal,
+    AccessLevel::Guest,
+    AccessLevel::Reporter,
+    AccessLevel::Developer,
+    AccessLevel::Maintainer,
+    AccessLevel::Owner,
+    AccessLevel::Admin,
+];
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct GitLabConfig {
-    token: String,
+    /// Name of the instance in plans, logs, `--only` and `--skip`, defaults to `gitlab`.
+    /// Has to be unique if several instances are configured.
+    name: Option<String>,
+    token: Secret,
     url: String,
     group_id: u64,
     owner_role: String,
     maintainer_role: String,
+    /// `disable` demotes removed members to guests instead of removing them.
+    #[serde(default)]
+    on_removed: OffboardingPolicy,
+    #[serde(default)]
+    protected_users: Vec<Pattern>,
+    /// Matched against the access level, e.g. `owner`.
+    #[serde(default)]
+    protected_roles: Vec<Pattern>,
+    /// Translates the source roles before they are compared to `owner_role` and
+    /// `maintainer_role`.
+    #[serde(default)]
+    role_mapping: RoleMapping,
 }
 
 #[derive(serde::Deserialize, PartialEq, Eq, Debug)]
 pub struct GitlabUser {
     id: u64,
     username: String,
+    /// Only present when the user was fetched as a group member.
+    access_level: Option<u64>,
+}
+
+/// Manages the members of a single GitLab group. The access level of a member is its
+/// only role, guests count as disabled members.
+pub struct GitLabClient {
+    client: AsyncGitlab,
+    group_id: u64,
+    owner_role: String,
+    maintainer_role: String,
+    /// GitLab ids of the group members and of the users about to be added, keyed by
+    /// username.
+    ids: HashMap<String, u64>,
 }
 
 impl Service for GitLabConfig {
-    async fn configure(&self, user_configs: &HashMap<String, UserConfig>) -> anyhow::Result<()> {
-        let client = Gitlab::new(self.url.to_owned(), self.token.to_owned())?;
+    type Client = GitLabClient;
 
-        let users = user_configs
-            .iter()
-            .filter(|user| {
-                user.1
-                    .roles
-                    .iter()
-                    .any(|r| r == &self.maintainer_role || r == &self.owner_role)
-            })
-            .inspect(|user| info!("gitlab: {:?}", user))
-            .filter_map::<Vec<GitlabUser>, _>(|user| {
-                api::users::Users::builder()
-                    .username(user.0)
-                    .build()
-                    .unwrap()
-                    .query(&client)
-                    .ok()
-            })
-            .filter_map(|mut v| v.pop())
-            .collect::<Vec<GitlabUser>>();
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("gitlab")
+    }
 
-        info!("gitlab: {:?}", users);
+    fn on_removed(&self) -> OffboardingPolicy {
+        self.on_removed
+    }
 
-        let current_group_members: Vec<GitlabUser> = api::groups::members::GroupMembers::builder()
-            .group(self.group_id)
-            .build()?
-            .query(&client)?;
-        info!("current_group_members: {:?}", current_group_members);
+    fn protected_users(&self) -> &[Pattern] {
+        &self.protected_users
+    }
 
-        let (users_to_update, users_to_remove): (Vec<_>, Vec<_>) = current_group_members
-            .into_iter()
-            .partition(|m| users.contains(m));
+    fn protected_roles(&self) -> &[Pattern] {
+        &self.protected_roles
+    }
 
-        info!("Users to update {:?}", users_to_update);
-        info!("Users to remove {:?}", users_to_remove);
+    fn role_mapping(&self) -> &RoleMapping {
+        &self.role_mapping
+    }
 
-        let users_to_create: Vec<_> = users
-            .into_iter()
-            .filter(|u| !users_to_update.contains(u))
+    async fn connect(&self) -> anyhow::Result<GitLabClient> {
+        Ok(GitLabClient {
+            client: GitlabBuilder::new(&self.url, self.token.expose())
+                .build_async()
+                .await?,
+            group_id: self.group_id,
+            owner_role: self.owner_role.clone(),
+            maintainer_role: self.maintainer_role.clone(),
+            ids: HashMap::new(),
+        })
+    }
+}
+
+impl ServiceClient for GitLabClient {
+    async fn fetch_accounts(&mut self) -> anyhow::Result<Vec<Account>> {
+        let members: Vec<GitlabUser> = api::paged(
+            api::groups::members::GroupMembers::builder()
+                .group(self.group_id)
+                .build()?,
+            api::Pagination::All,
+        )
+        .query_async(&self.client)
+        .await?;
+        info!("current_group_members: {:?}", members);
+
+        self.ids = members
+            .iter()
+            .map(|member| (member.username.clone(), member.id))
             .collect();
+        Ok(members
+            .into_iter()
+            .map(|member| Account {
+                username: member.username,
+                fields: BTreeMap::new(),
+                enabled: member.access_level > Some(AccessLevel::Guest.as_u64()),
+                roles: member
+                    .access_level
+                    .and_then(|level| ACCESS_LEVELS.iter().find(|l| l.as_u64() == level))
+                    .map(|level| level.as_str().to_string())
+                    .into_iter()
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Users with the owner role become owners, users with the maintainer role
+    /// maintainers. Everyone else does not belong into the group.
+    fn desired_account(&self, username: &str, user: &UserConfig) -> Option<Account> {
+        let level = if user.role_names().any(|role| role == self.owner_role) {
+            AccessLevel::Owner
+        } else if user.role_names().any(|role| role == self.maintainer_role) {
+            AccessLevel::Maintainer
+        } else {
+            return None;
+        };
+        Some(Account {
+            username: username.to_string(),
+            fields: BTreeMap::new(),
+            enabled: true,
+            roles: BTreeSet::from([level.as_str().to_string()]),
+        })
+    }
 
-        info!("Users to create {:?}", users_to_create);
-
-        users_to_create.iter().try_for_each(|user| {
-            let _ = api::ignore(
-                api::groups::members::AddGroupMember::builder()
-                    .group(self.group_id)
-                    .user(user.id)
-                    .access_level(
-                        if user_configs[&user.username]
-                            .roles
-                            .contains(&self.owner_role)
-                        {
-                            AccessLevel::Owner
-                        } else {
-                            AccessLevel::Maintainer
-                        },
-                    )
-                    .build()?,
-            )
-            .query(&client)?;
-            anyhow::Ok(())
-        })?;
-
-        users_to_update.iter().try_for_each(|user| {
-            let _ = api::ignore(
-                api::groups::members::EditGroupMember::builder()
-                    .access_level(
-                        if user_configs[&user.username]
-                            .roles
-                            .contains(&self.owner_role)
-                        {
-                            AccessLevel::Owner
-                        } else {
-                            AccessLevel::Maintainer
-                        },
-                    )
-                    .user(user.id)
-                    .group(self.group_id)
-                    .build()?,
-            )
-            .query(&client)?;
-            anyhow::Ok(())
-        })?;
-
-        users_to_remove.iter().try_for_each(|user| {
-            let _ = api::ignore(
-                api::groups::members::RemoveGroupMember::builder()
-                    .user(user.id)
-                    .group(self.group_id)
-                    .build()?,
-            )
-            .query(&client)?;
-            anyhow::Ok(())
-        })?;
+    /// The tool only adds existing GitLab users to the group, users without a GitLab
+    /// account are skipped.
+    async fn uncreatable(&mut self, usernames: &[&str]) -> anyhow::Result<Vec<String>> {
+        let mut unknown = Vec::new();
+        for username in usernames {
+            let mut users: Vec<GitlabUser> = api::users::Users::builder()
+                .username(*username)
+                .build()?
+                .query_async(&self.client)
+                .await?;
+            match users.pop() {
+                Some(user) => {
+                    self.ids.insert(username.to_string(), user.id);
+                }
+                None => unknown.push(username.to_string()),
+            }
+        }
+        Ok(unknown)
+    }
+
+    async fn apply(&mut self, action: &Action) -> anyhow::Result<()> {
+        match action {
+            Action::CreateUser {
+                username, roles, ..
+            } => {
+                let level = roles
+                    .iter()
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("no access level for {username}"))?;
+                api::ignore(
+                    api::groups::members::AddGroupMember::builder()
+                        .group(self.group_id)
+                        .user(self.id(username)?)
+                        .access_level(access_level(level)?)
+                        .build()?,
+                )
+                .query_async(&self.client)
+                .await?;
+            }
+            Action::GrantRole { username, role } => {
+                api::ignore(
+                    api::groups::members::EditGroupMember::builder()
+                        .access_level(access_level(role)?)
+                        .user(self.id(username)?)
+                        .group(self.group_id)
+                        .build()?,
+                )
+                .query_async(&self.client)
+                .await?;
+            }
+            Action::RevokeRole { username, role } => {
+                // A member always has exactly one access level, the old one is
+                // replaced by the accompanying grant.
+                debug!("gitlab: {username} loses access level {role}");
+            }
+            Action::DeleteUser { username } => {
+                api::ignore(
+                    api::groups::members::RemoveGroupMember::builder()
+                        .user(self.id(username)?)
+                        .group(self.group_id)
+                        .build()?,
+                )
+                .query_async(&self.client)
+                .await?;
+            }
+            Action::DisableUser { username } => {
+                api::ignore(
+                    api::groups::members::EditGroupMember::builder()
+                        .access_level(AccessLevel::Guest)
+                        .user(self.id(username)?)
+                        .group(self.group_id)
+                        .build()?,
+                )
+                .query_async(&self.client)
+                .await?;
+            }
+            Action::EnableUser { username } => {
+                // Re-enabled members get their access level from the accompanying grant.
+                debug!("gitlab: {username} is no longer a guest");
+            }
+            Action::UpdateUser { .. } => {
+                anyhow::bail!("gitlab group members have no profile: {action}")
+            }
+        }
         Ok(())
     }
 }
+
+impl GitLabClient {
+    fn id(&self, username: &str) -> anyhow::Result<u64> {
+        self.ids
+            .get(username)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("{username} is not a member of the gitlab group"))
+    }
+}
+
+fn access_level(name: &str) -> anyhow::Result<AccessLevel> {
+    ACCESS_LEVELS
+        .into_iter()
+        .find(|level| level.as_str() == name)
+        .ok_or_else(|| anyhow::anyhow!("unknown gitlab access level {name}"))
+}
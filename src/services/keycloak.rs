@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use log::*;
 use oauth2::basic::BasicClient;
@@ -6,22 +6,43 @@ use oauth2::reqwest::async_http_client;
 use oauth2::AccessToken;
 use oauth2::ClientId;
 use oauth2::TokenResponse;
-use serde_json::json;
+use serde_json::{json, Value};
 
-use crate::services::Service;
+use crate::pattern::Pattern;
+use crate::role_mapping::RoleMapping;
+use crate::secret::Secret;
+use crate::services::plan::{Account, Action, OffboardingPolicy};
+use crate::services::{Service, ServiceClient};
 use crate::true_bool;
 use crate::UserConfig;
 
-#[derive(serde::Deserialize, serde::Serialize, Debug)]
+/// Number of users requested per page when listing the realm.
+const PAGE_SIZE: usize = 100;
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct KeycloakConfig {
+    /// Name of the instance in plans, logs, `--only` and `--skip`, defaults to `keycloak`.
+    /// Has to be unique if several instances are configured.
+    pub name: Option<String>,
     pub url: String,
     pub realm: String,
     pub username: String,
-    pub password: String,
+    pub password: Secret,
     pub client_id: String,
+    #[serde(default)]
+    pub on_removed: OffboardingPolicy,
+    #[serde(default)]
+    pub protected_users: Vec<Pattern>,
+    #[serde(default)]
+    pub protected_roles: Vec<Pattern>,
+    /// Translates the source roles into the roles of this instance.
+    #[serde(default)]
+    pub role_mapping: RoleMapping,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
 struct KeycloakUser {
     id: String,
     username: String,
@@ -32,11 +53,14 @@ struct KeycloakUser {
     enabled: bool,
 }
 
-struct KeycloakClient {
+pub struct KeycloakClient {
     base_url: String,
     realm: String,
     token: AccessToken,
     reqwest_client: reqwest::Client,
+    /// Keycloak ids of the known users, keyed by username.
+    ids: HashMap<String, String>,
+    realm_roles: Vec<KeycloakRole>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
@@ -46,39 +70,135 @@ struct KeycloakRole {
 }
 
 impl Service for KeycloakConfig {
-    async fn configure(&self, users: &HashMap<String, UserConfig>) -> anyhow::Result<()> {
-        let client = KeycloakClient::new(
+    type Client = KeycloakClient;
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("keycloak")
+    }
+
+    fn on_removed(&self) -> OffboardingPolicy {
+        self.on_removed
+    }
+
+    fn protected_users(&self) -> &[Pattern] {
+        &self.protected_users
+    }
+
+    fn protected_roles(&self) -> &[Pattern] {
+        &self.protected_roles
+    }
+
+    fn role_mapping(&self) -> &RoleMapping {
+        &self.role_mapping
+    }
+
+    async fn connect(&self) -> anyhow::Result<KeycloakClient> {
+        KeycloakClient::new(
             self.url.clone(),
             self.realm.clone(),
             self.username.clone(),
-            self.password.clone(),
+            self.password.expose().to_string(),
             self.client_id.clone(),
         )
-        .await?;
-
-        let keycloak_users = client.get_all_users().await?;
-
-        let users_to_create = users
-            .iter()
-            .filter(|user| !keycloak_users.iter().any(|k| *user.0 == k.username))
-            .collect::<HashMap<_, _>>();
-
-        client.create_users(&users_to_create).await?;
+        .await
+    }
+}
 
-        let users_to_update = keycloak_users
-            .iter()
-            .filter(|keycloak_user| users.contains_key(&keycloak_user.username))
-            .collect::<Vec<_>>();
+impl ServiceClient for KeycloakClient {
+    async fn fetch_accounts(&mut self) -> anyhow::Result<Vec<Account>> {
+        self.realm_roles = self.get_all_realm_roles().await?;
 
-        client.update_users(&users_to_update, &users).await?;
-        client.update_roles(&users_to_update, &users).await?;
+        let mut accounts = Vec::new();
+        for user in self.get_all_users().await? {
+            let roles = self
+                .get_realm_roles(&user)
+                .await?
+                .into_iter()
+                .map(|role| role.name)
+                .collect();
+            self.ids.insert(user.username.clone(), user.id.clone());
+            accounts.push(Account {
+                fields: [
+                    ("firstName", user.first_name),
+                    ("lastName", user.last_name),
+                    ("email", user.email),
+                ]
+                .into_iter()
+                .filter_map(|(field, value)| Some((field.to_string(), Value::from(value?))))
+                .collect(),
+                username: user.username,
+                enabled: user.enabled,
+                roles,
+            });
+        }
+        Ok(accounts)
+    }
 
-        let users_to_delete = keycloak_users
-            .iter()
-            .filter(|keycloak_user| !users.contains_key(&keycloak_user.username))
-            .collect::<Vec<_>>();
-        client.delete_users(&users_to_delete).await?;
+    fn desired_account(&self, username: &str, user: &UserConfig) -> Option<Account> {
+        Some(Account {
+            username: username.to_string(),
+            fields: [
+                ("firstName", &user.first_name),
+                ("lastName", &user.last_name),
+                ("email", &user.email),
+            ]
+            .into_iter()
+            .filter_map(|(field, value)| Some((field.to_string(), Value::from(value.clone()?))))
+            .collect(),
+            enabled: user.enabled,
+            roles: user.role_names().map(str::to_string).collect(),
+        })
+    }
 
+    async fn apply(&mut self, action: &Action) -> anyhow::Result<()> {
+        match action {
+            Action::CreateUser {
+                username,
+                fields,
+                enabled,
+                roles,
+            } => {
+                let id = self.create_user(username, fields, *enabled).await?;
+                let mut roles_to_add = Vec::new();
+                for role in roles {
+                    roles_to_add.push(self.realm_role(role).await?);
+                }
+                self.add_user_roles(&id, &roles_to_add).await?;
+                self.ids.insert(username.clone(), id);
+            }
+            Action::UpdateUser { username, changes } => {
+                let body = changes
+                    .iter()
+                    .map(|change| (change.field.clone(), change.to.clone()))
+                    .collect::<serde_json::Map<_, _>>();
+                self.update_user(self.id(username)?, &Value::Object(body))
+                    .await?;
+            }
+            Action::GrantRole { username, role } => {
+                let role = self.realm_role(role).await?;
+                self.add_user_roles(self.id(username)?, &[role]).await?;
+            }
+            Action::RevokeRole { username, role } => {
+                let role = self
+                    .realm_roles
+                    .iter()
+                    .find(|r| r.name == *role)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("unknown realm role {role}"))?;
+                self.remove_user_roles(self.id(username)?, &[role]).await?;
+            }
+            Action::EnableUser { username } => {
+                self.update_user(self.id(username)?, &json!({ "enabled": true }))
+                    .await?;
+            }
+            Action::DisableUser { username } => {
+                self.update_user(self.id(username)?, &json!({ "enabled": false }))
+                    .await?;
+            }
+            Action::DeleteUser { username } => {
+                self.delete_user(self.id(username)?).await?;
+            }
+        }
         Ok(())
     }
 }
@@ -123,87 +243,99 @@ impl KeycloakClient {
             realm,
             token,
             reqwest_client: reqwest::Client::new(),
+            ids: HashMap::new(),
+            realm_roles: Vec::new(),
         })
     }
 
-    async fn create_users(&self, users: &HashMap<&String, &UserConfig>) -> anyhow::Result<()> {
-        for user in users {
-            let user = self
-                .reqwest_client
-                .post(format!(
-                    "{}/admin/realms/{}/users",
-                    self.base_url, self.realm
-                ))
-                .bearer_auth(&self.token.secret())
-                .json(&json!(
-                    {
-                        "username": user.0,
-                        "firstName": user.1.first_name,
-                        "lastName": user.1.last_name,
-                        "email": user.1.email,
-                        "enabled": user.1.enabled,
-                    }
-                ))
-                .send()
-                .await?
-                .text()
-                .await?;
-            info!("Created User: {:?}", user);
-        }
-        Ok(())
+    fn id(&self, username: &str) -> anyhow::Result<&str> {
+        self.ids
+            .get(username)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow::anyhow!("unknown keycloak user {username}"))
     }
 
-    async fn get_all_users(&self) -> anyhow::Result<Vec<KeycloakUser>> {
-        debug!("Getting all users from Keycloak");
-        // Create a request
-        Ok(self
+    /// Creates a user and returns its id.
+    async fn create_user(
+        &self,
+        username: &str,
+        fields: &BTreeMap<String, Value>,
+        enabled: bool,
+    ) -> anyhow::Result<String> {
+        let mut body = json!({ "username": username, "enabled": enabled });
+        body.as_object_mut()
+            .unwrap()
+            .extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let response = self
             .reqwest_client
-            .get(format!(
+            .post(format!(
                 "{}/admin/realms/{}/users",
                 self.base_url, self.realm
             ))
             .bearer_auth(self.token.secret())
+            .json(&body)
             .send()
             .await?
-            .json::<Vec<KeycloakUser>>()
-            .await?)
+            .error_for_status()?;
+        // Keycloak answers with the location of the new user, ending in its id
+        response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| location.rsplit('/').next())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("keycloak did not return the id of {username}"))
     }
 
-    async fn disable_users(&self, users: &Vec<&KeycloakUser>) -> anyhow::Result<()> {
-        for user in users {
-            debug!("Disabling user: {}", user.username);
-            let _ = self
+    async fn get_all_users(&self) -> anyhow::Result<Vec<KeycloakUser>> {
+        debug!("Getting all users from Keycloak");
+        let mut users = Vec::new();
+        loop {
+            let page = self
                 .reqwest_client
-                .put(format!(
-                    "{}/admin/realms/{}/users/{}",
-                    self.base_url, self.realm, user.id
+                .get(format!(
+                    "{}/admin/realms/{}/users",
+                    self.base_url, self.realm
                 ))
+                .query(&[("first", users.len()), ("max", PAGE_SIZE)])
                 .bearer_auth(self.token.secret())
-                .json(&json!({
-                    "enabled": false
-                }))
                 .send()
+                .await?
+                .error_for_status()?
+                .json::<Vec<KeycloakUser>>()
                 .await?;
+            let last_page = page.len() < PAGE_SIZE;
+            users.extend(page);
+            if last_page {
+                return Ok(users);
+            }
         }
+    }
+
+    async fn update_user(&self, id: &str, body: &Value) -> anyhow::Result<()> {
+        self.reqwest_client
+            .put(format!(
+                "{}/admin/realms/{}/users/{}",
+                self.base_url, self.realm, id
+            ))
+            .bearer_auth(self.token.secret())
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 
-    async fn delete_users(&self, users: &Vec<&KeycloakUser>) -> anyhow::Result<()> {
-        for user in users {
-            info!("Deleting user: {}", user.username);
-            let _ = self
-                .reqwest_client
-                .delete(format!(
-                    "{}/admin/realms/{}/users/{}",
-                    self.base_url, self.realm, user.id
-                ))
-                .bearer_auth(self.token.secret())
-                .json(&json!({
-                    "enabled": false
-                }))
-                .send()
-                .await?;
-        }
+    async fn delete_user(&self, id: &str) -> anyhow::Result<()> {
+        self.reqwest_client
+            .delete(format!(
+                "{}/admin/realms/{}/users/{}",
+                self.base_url, self.realm, id
+            ))
+            .bearer_auth(self.token.secret())
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 
@@ -218,6 +350,7 @@ impl KeycloakClient {
             .bearer_auth(self.token.secret())
             .send()
             .await?
+            .error_for_status()?
             .json::<Vec<KeycloakRole>>()
             .await?)
     }
@@ -233,11 +366,27 @@ impl KeycloakClient {
             .bearer_auth(self.token.secret())
             .send()
             .await?
+            .error_for_status()?
             .json::<Vec<KeycloakRole>>()
             .await?)
     }
 
-    async fn create_realm_role(&self, role: String) -> anyhow::Result<()> {
+    /// Looks up a realm role by name, creating it if it does not exist yet.
+    async fn realm_role(&mut self, name: &str) -> anyhow::Result<KeycloakRole> {
+        if let Some(role) = self.realm_roles.iter().find(|r| r.name == name) {
+            return Ok(role.clone());
+        }
+        info!("Create role {}", name);
+        self.create_realm_role(name).await?;
+        self.realm_roles = self.get_all_realm_roles().await?;
+        self.realm_roles
+            .iter()
+            .find(|r| r.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("failed to create realm role {name}"))
+    }
+
+    async fn create_realm_role(&self, role: &str) -> anyhow::Result<()> {
         self.reqwest_client
             .post(format!(
                 "{}/admin/realms/{}/roles",
@@ -246,137 +395,41 @@ impl KeycloakClient {
             .bearer_auth(self.token.secret())
             .json(&json!({ "name": role }))
             .send()
-            .await?;
-        Ok(())
-    }
-
-    fn roles_to_add(
-        config_roles: &Vec<String>,
-        keycloak_roles: &Vec<KeycloakRole>,
-        existing_roles: &Vec<KeycloakRole>,
-    ) -> Vec<KeycloakRole> {
-        keycloak_roles
-            .iter()
-            .filter(|role| config_roles.contains(&role.name))
-            .filter(|role| !existing_roles.contains(role))
-            .cloned()
-            .collect()
-    }
-
-    fn roles_to_remove(
-        config_roles: &Vec<String>,
-        keycloak_roles: &Vec<KeycloakRole>,
-    ) -> Vec<KeycloakRole> {
-        keycloak_roles
-            .iter()
-            .filter(|role| !config_roles.contains(&role.name))
-            .cloned()
-            .collect()
-    }
-
-    async fn update_roles(
-        &self,
-        users_keycloak: &Vec<&KeycloakUser>,
-        user_configs: &HashMap<String, UserConfig>,
-    ) -> anyhow::Result<()> {
-        debug!("Updating roles for users");
-        let keycloak_roles = self.get_all_realm_roles().await?;
-        for roles_to_add in user_configs
-            .iter()
-            .map(|(_, users)| users.roles.clone())
-            .flatten()
-            .filter(|r| !keycloak_roles.iter().any(|kr| kr.name == *r))
-        {
-            info!("Create role {}", roles_to_add);
-            self.create_realm_role(roles_to_add).await?;
-        }
-        let keycloak_roles = self.get_all_realm_roles().await?;
-
-        for user in users_keycloak {
-            let configured_roles = user_configs[&user.username].roles.clone();
-            let existing_roles = self.get_realm_roles(user).await?;
-            let roles_to_add =
-                Self::roles_to_add(&configured_roles, &keycloak_roles, &existing_roles);
-            let roles_to_remove = Self::roles_to_remove(&configured_roles, &existing_roles);
-
-            self.update_user_roles(&user.id, &roles_to_add, &roles_to_remove)
-                .await?;
-        }
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 
-    async fn update_user_roles(
-        &self,
-        user_id: &String,
-        roles_to_add: &Vec<KeycloakRole>,
-        roles_to_remove: &Vec<KeycloakRole>,
-    ) -> anyhow::Result<()> {
-        debug!("Updating roles for user: {}", user_id);
-        if !roles_to_add.is_empty() {
-            match self
-                .reqwest_client
-                .post(format!(
-                    "{}/admin/realms/{}/users/{}/role-mappings/realm",
-                    self.base_url, self.realm, user_id
-                ))
-                .bearer_auth(self.token.secret())
-                .json(&json!(roles_to_add))
-                .send()
-                .await?
-                .status()
-            {
-                reqwest::StatusCode::NO_CONTENT => {
-                    info!("Added roles: {:?} to {:?}", roles_to_add, user_id)
-                }
-                status => error!("Failed to add roles to user: {}", status),
-            }
+    async fn add_user_roles(&self, user_id: &str, roles: &[KeycloakRole]) -> anyhow::Result<()> {
+        if roles.is_empty() {
+            return Ok(());
         }
+        debug!("Adding roles {:?} to user: {}", roles, user_id);
         self.reqwest_client
-            .delete(format!(
+            .post(format!(
                 "{}/admin/realms/{}/users/{}/role-mappings/realm",
                 self.base_url, self.realm, user_id
             ))
             .bearer_auth(self.token.secret())
-            .json(&json!(roles_to_remove))
+            .json(&json!(roles))
             .send()
-            .await?;
-        Ok(())
-    }
-
-    async fn update_users(
-        &self,
-        users: &Vec<&KeycloakUser>,
-        user_configs: &HashMap<String, UserConfig>,
-    ) -> anyhow::Result<()> {
-        for user in users {
-            let user_config = &user_configs[&user.username];
-            self.update_user(&user, &user_config).await?;
-        }
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 
-    async fn update_user(
-        &self,
-        user: &KeycloakUser,
-        user_config: &UserConfig,
-    ) -> anyhow::Result<()> {
+    async fn remove_user_roles(&self, user_id: &str, roles: &[KeycloakRole]) -> anyhow::Result<()> {
+        debug!("Removing roles {:?} from user: {}", roles, user_id);
         self.reqwest_client
-            .put(format!(
-                "{}/admin/realms/{}/users/{}",
-                self.base_url, self.realm, user.id
+            .delete(format!(
+                "{}/admin/realms/{}/users/{}/role-mappings/realm",
+                self.base_url, self.realm, user_id
             ))
             .bearer_auth(self.token.secret())
-            .json(&json!(
-                {
-                    "firstName": user_config.first_name,
-                    "lastName": user_config.last_name,
-                    "email": user_config.email,
-                    "enabled": user_config.enabled,
-                    "username": user.username
-                }
-            ))
+            .json(&json!(roles))
             .send()
-            .await?;
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 }
@@ -1,11 +1,74 @@
 use std::collections::HashMap;
 
+use crate::pattern::Pattern;
+use crate::role_mapping::RoleMapping;
 use crate::UserConfig;
 
 pub mod authentik;
 pub mod gitlab;
 pub mod keycloak;
+pub mod plan;
 
+use plan::{Account, Action, Inspection, OffboardingPolicy, RunOptions, ServiceRun};
+
+/// A configured target service. Services only describe how to reach the target; the
+/// diffing and applying is done by the shared engine in [`plan`].
 pub trait Service {
-    async fn configure(&self, users: &HashMap<String, UserConfig>) -> anyhow::Result<()>;
+    type Client: ServiceClient;
+
+    /// Name of the service used in plans and log messages.
+    fn name(&self) -> &str;
+
+    /// What to do with accounts whose user was removed from the configuration.
+    fn on_removed(&self) -> OffboardingPolicy;
+
+    /// Accounts the tool must never touch, matched by username.
+    fn protected_users(&self) -> &[Pattern];
+
+    /// Accounts holding any of these roles are never touched either.
+    fn protected_roles(&self) -> &[Pattern];
+
+    /// Translates the source roles into the roles of this service before the desired
+    /// accounts are computed.
+    fn role_mapping(&self) -> &RoleMapping;
+
+    async fn connect(&self) -> anyhow::Result<Self::Client>;
+
+    /// Synchronises the service with `users`. In a dry run the planned changes are
+    /// only printed and nothing is written to the service.
+    async fn configure(
+        &self,
+        users: &HashMap<String, UserConfig>,
+        options: &RunOptions,
+    ) -> ServiceRun {
+        plan::sync(self, users, options).await
+    }
+
+    /// The current and desired account of `username`, without changing anything.
+    async fn inspect(
+        &self,
+        username: &str,
+        user: Option<&UserConfig>,
+    ) -> anyhow::Result<Inspection> {
+        plan::inspect(self, username, user).await
+    }
+}
+
+/// A connection to a target service.
+pub trait ServiceClient {
+    /// Fetches all accounts the service currently manages.
+    async fn fetch_accounts(&mut self) -> anyhow::Result<Vec<Account>>;
+
+    /// The account `user` should have in this service, or `None` if the user does not
+    /// belong into it. Called after [`ServiceClient::fetch_accounts`].
+    fn desired_account(&self, username: &str, user: &UserConfig) -> Option<Account>;
+
+    /// Those of the missing `usernames` the service cannot create an account for, e.g.
+    /// because it only manages memberships of users that have to exist already. They are
+    /// left out of the plan. Called after [`ServiceClient::fetch_accounts`].
+    async fn uncreatable(&mut self, _usernames: &[&str]) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    async fn apply(&mut self, action: &Action) -> anyhow::Result<()>;
 }
@@ -0,0 +1,995 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::history::History;
+use crate::metrics;
+use crate::pattern::matches_any;
+use crate::role_assignment::RoleAssignment;
+use crate::services::{Service, ServiceClient};
+use crate::UserConfig;
+
+/// An account as the reconciliation engine sees it, either as it currently exists in a
+/// service or as it should exist according to the user configuration.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Account {
+    pub username: String,
+    /// Service specific profile fields, keyed by the name the service's API uses.
+    /// In a desired account only the fields that should be enforced are present.
+    pub fields: BTreeMap<String, Value>,
+    pub enabled: bool,
+    pub roles: BTreeSet<String>,
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.enabled { "enabled" } else { "disabled" })?;
+        for (field, value) in &self.fields {
+            write!(f, ", {field}={value}")?;
+        }
+        write!(
+            f,
+            ", roles: {}",
+            self.roles.iter().cloned().collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: Option<Value>,
+    pub to: Value,
+}
+
+/// A single change to a service. Roles of new users are part of `CreateUser`, all other
+/// role changes are expressed as `GrantRole` and `RevokeRole`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    CreateUser {
+        username: String,
+        fields: BTreeMap<String, Value>,
+        enabled: bool,
+        roles: BTreeSet<String>,
+    },
+    UpdateUser {
+        username: String,
+        changes: Vec<FieldChange>,
+    },
+    GrantRole {
+        username: String,
+        role: String,
+    },
+    RevokeRole {
+        username: String,
+        role: String,
+    },
+    EnableUser {
+        username: String,
+    },
+    DisableUser {
+        username: String,
+    },
+    DeleteUser {
+        username: String,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::CreateUser {
+                username,
+                fields,
+                enabled,
+                roles,
+            } => {
+                write!(f, "+ create user {username}")?;
+                for (field, value) in fields {
+                    write!(f, ", {field}={value}")?;
+                }
+                if !enabled {
+                    write!(f, ", disabled")?;
+                }
+                if !roles.is_empty() {
+                    write!(
+                        f,
+                        ", roles: {}",
+                        roles.iter().cloned().collect::<Vec<_>>().join(", ")
+                    )?;
+                }
+                Ok(())
+            }
+            Action::UpdateUser { username, changes } => {
+                write!(f, "~ update user {username}: ")?;
+                for (i, change) in changes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match &change.from {
+                        Some(from) => write!(f, "{} {} -> {}", change.field, from, change.to)?,
+                        None => write!(f, "{} unset -> {}", change.field, change.to)?,
+                    }
+                }
+                Ok(())
+            }
+            Action::GrantRole { username, role } => write!(f, "+ grant role {role} to {username}"),
+            Action::RevokeRole { username, role } => {
+                write!(f, "- revoke role {role} from {username}")
+            }
+            Action::EnableUser { username } => write!(f, "+ enable user {username}"),
+            Action::DisableUser { username } => write!(f, "- disable user {username}"),
+            Action::DeleteUser { username } => write!(f, "- delete user {username}"),
+        }
+    }
+}
+
+impl Action {
+    /// The account the action changes.
+    pub fn username(&self) -> &str {
+        match self {
+            Action::CreateUser { username, .. }
+            | Action::UpdateUser { username, .. }
+            | Action::GrantRole { username, .. }
+            | Action::RevokeRole { username, .. }
+            | Action::EnableUser { username }
+            | Action::DisableUser { username }
+            | Action::DeleteUser { username } => username,
+        }
+    }
+}
+
+/// What happens to accounts that exist in a service but no longer in the user
+/// configuration.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OffboardingPolicy {
+    #[default]
+    Delete,
+    /// Keep the account, but disable it so it can be handed over or audited.
+    Disable,
+    /// Leave the account untouched.
+    Ignore,
+}
+
+/// Limits on how many accounts a single run may offboard. Exceeding either limit aborts
+/// the run of the service before anything is applied, unless it is forced.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyBrake {
+    /// Maximum number of accounts deleted or disabled per service and run.
+    pub max_removals: usize,
+    /// Maximum share of a service's current accounts, in percent, deleted or disabled
+    /// per run.
+    pub max_removal_percent: f64,
+}
+
+impl Default for SafetyBrake {
+    fn default() -> Self {
+        SafetyBrake {
+            max_removals: 20,
+            max_removal_percent: 30.0,
+        }
+    }
+}
+
+impl SafetyBrake {
+    /// Whether removing `removals` of `total` accounts stays within both limits.
+    pub fn allows(&self, removals: usize, total: usize) -> bool {
+        removals <= self.max_removals && percent(removals, total) <= self.max_removal_percent
+    }
+}
+
+/// `part` as a share of `total`, in percent.
+pub fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Restricts a sync run to parts of the configuration.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Scope {
+    /// Only sync the services with these names, all if empty.
+    pub services: Vec<String>,
+    /// Never sync the services with these names.
+    pub skip: Vec<String>,
+    /// Only sync the account with this username, no other account is touched.
+    pub user: Option<String>,
+}
+
+impl Scope {
+    pub fn includes_service(&self, name: &str) -> bool {
+        (self.services.is_empty() || self.services.iter().any(|service| service == name))
+            && !self.skip.iter().any(|service| service == name)
+    }
+
+    pub fn includes_user(&self, username: &str) -> bool {
+        self.user.as_deref().is_none_or(|user| user == username)
+    }
+
+    /// Whether every service and user is in scope.
+    pub fn is_everything(&self) -> bool {
+        self.services.is_empty() && self.skip.is_empty() && self.user.is_none()
+    }
+}
+
+/// How a sync run treats the computed plans.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Only print the plans.
+    pub dry_run: bool,
+    /// Apply plans even if they exceed the safety brake.
+    pub force: bool,
+    pub safety_brake: SafetyBrake,
+    pub scope: Scope,
+    /// Sync the services concurrently instead of one after another.
+    pub parallel: bool,
+    /// Path of the JSON report written after every run, `-` for stdout.
+    pub report: Option<String>,
+    /// Log every applied change is recorded in.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Per-account history every applied change is added to.
+    pub history: Option<Arc<History>>,
+}
+
+impl RunOptions {
+    /// Prints the human readable plans and source diffs. They go to stderr if the report
+    /// is written to stdout, so it stays machine readable.
+    pub fn print(&self, text: &impl fmt::Display) {
+        if self.report.as_deref() == Some("-") {
+            eprint!("{text}");
+        } else {
+            print!("{text}");
+        }
+    }
+}
+
+/// The changes needed to bring a single service in line with the user configuration.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Plan {
+    pub service: String,
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    /// Computes the actions that turn the `current` accounts of a service into the
+    /// `desired` ones, treating accounts missing from `desired` according to `on_removed`.
+    /// Actions are ordered creates, updates, role changes and offboarding, each sorted by
+    /// username.
+    pub fn compute(
+        service: &str,
+        desired: &BTreeMap<String, Account>,
+        current: &[Account],
+        on_removed: OffboardingPolicy,
+    ) -> Self {
+        let current = current
+            .iter()
+            .map(|account| (account.username.clone(), account))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut creates = Vec::new();
+        let mut updates = Vec::new();
+        let mut role_changes = Vec::new();
+        let mut offboarding = Vec::new();
+
+        for (username, wanted) in desired {
+            let Some(existing) = current.get(username) else {
+                creates.push(Action::CreateUser {
+                    username: username.clone(),
+                    fields: wanted.fields.clone(),
+                    enabled: wanted.enabled,
+                    roles: wanted.roles.clone(),
+                });
+                continue;
+            };
+
+            let changes = wanted
+                .fields
+                .iter()
+                .filter(|(field, value)| existing.fields.get(*field) != Some(value))
+                .map(|(field, value)| FieldChange {
+                    field: field.clone(),
+                    from: existing.fields.get(field).cloned(),
+                    to: value.clone(),
+                })
+                .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                updates.push(Action::UpdateUser {
+                    username: username.clone(),
+                    changes,
+                });
+            }
+            match (existing.enabled, wanted.enabled) {
+                (true, false) => updates.push(Action::DisableUser {
+                    username: username.clone(),
+                }),
+                (false, true) => updates.push(Action::EnableUser {
+                    username: username.clone(),
+                }),
+                _ => {}
+            }
+
+            role_changes.extend(wanted.roles.difference(&existing.roles).map(|role| {
+                Action::GrantRole {
+                    username: username.clone(),
+                    role: role.clone(),
+                }
+            }));
+            role_changes.extend(existing.roles.difference(&wanted.roles).map(|role| {
+                Action::RevokeRole {
+                    username: username.clone(),
+                    role: role.clone(),
+                }
+            }));
+        }
+
+        for (username, existing) in current.iter().filter(|(u, _)| !desired.contains_key(*u)) {
+            match on_removed {
+                OffboardingPolicy::Delete => offboarding.push(Action::DeleteUser {
+                    username: username.clone(),
+                }),
+                OffboardingPolicy::Disable if existing.enabled => {
+                    offboarding.push(Action::DisableUser {
+                        username: username.clone(),
+                    })
+                }
+                OffboardingPolicy::Disable | OffboardingPolicy::Ignore => {}
+            }
+        }
+
+        Plan {
+            service: service.to_string(),
+            actions: [creates, updates, role_changes, offboarding].concat(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Number of accounts the plan deletes or disables.
+    pub fn removals(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| {
+                matches!(
+                    action,
+                    Action::DeleteUser { .. } | Action::DisableUser { .. }
+                )
+            })
+            .count()
+    }
+
+    /// Fails if the plan removes more of the `current_accounts` than `brake` allows.
+    fn check_safety_brake(
+        &self,
+        brake: &SafetyBrake,
+        current_accounts: usize,
+    ) -> anyhow::Result<()> {
+        let removals = self.removals();
+        if !brake.allows(removals, current_accounts) {
+            anyhow::bail!(
+                "{}: refusing to delete or disable {} of {} accounts ({:.0}%), the limit is {} accounts or {}%; rerun with --force if this is intended",
+                self.service,
+                removals,
+                current_accounts,
+                percent(removals, current_accounts),
+                brake.max_removals,
+                brake.max_removal_percent
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for action in &self.actions {
+            writeln!(f, "[{}] {}", self.service, action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the account matches the protected users or holds a protected role of
+/// `service`. Protected accounts are never created, updated or offboarded.
+fn is_protected<S: Service + ?Sized>(service: &S, account: &Account) -> bool {
+    matches_any(service.protected_users(), &account.username)
+        || account
+            .roles
+            .iter()
+            .any(|role| matches_any(service.protected_roles(), role))
+}
+
+/// The state of a single account in a service.
+#[derive(Debug)]
+pub struct Inspection {
+    pub current: Option<Account>,
+    pub desired: Option<Account>,
+    pub protected: bool,
+}
+
+/// Looks up `username` in `service` without changing anything.
+pub async fn inspect<S: Service + ?Sized>(
+    service: &S,
+    username: &str,
+    user: Option<&UserConfig>,
+) -> anyhow::Result<Inspection> {
+    let mut client = service.connect().await?;
+    let current = client
+        .fetch_accounts()
+        .await?
+        .into_iter()
+        .find(|account| account.username == username);
+    let mut desired =
+        user.and_then(|user| client.desired_account(username, &mapped_user(service, user)));
+    if current.is_none() && desired.is_some() && !client.uncreatable(&[username]).await?.is_empty()
+    {
+        desired = None;
+    }
+    Ok(Inspection {
+        protected: matches_any(service.protected_users(), username)
+            || current
+                .as_ref()
+                .is_some_and(|account| is_protected(service, account)),
+        current,
+        desired,
+    })
+}
+
+/// Outcome of syncing a single service.
+#[derive(Serialize, Debug, Clone)]
+pub struct ServiceRun {
+    pub service: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_seconds: f64,
+    pub plan: Plan,
+    /// Error that stopped the service before any change was applied.
+    pub error: Option<String>,
+    /// Changes that could not be applied.
+    pub failed: Vec<FailedAction>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FailedAction {
+    pub action: Action,
+    pub error: String,
+}
+
+impl ServiceRun {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.failed.is_empty()
+    }
+}
+
+/// Fetches the current state of `service`, computes the plan for `users`, prints it and,
+/// unless this is a dry run, applies it action by action. A failing action does not stop
+/// the remaining ones, all errors are collected in the returned [`ServiceRun`].
+pub async fn sync<S: Service + ?Sized>(
+    service: &S,
+    users: &HashMap<String, UserConfig>,
+    options: &RunOptions,
+) -> ServiceRun {
+    let timer = metrics::run_timer(service.name());
+    let mut run = ServiceRun {
+        service: service.name().to_string(),
+        started_at: Utc::now(),
+        duration_seconds: 0.0,
+        plan: Plan {
+            service: service.name().to_string(),
+            actions: Vec::new(),
+        },
+        error: None,
+        failed: Vec::new(),
+    };
+
+    let Prepared {
+        mut client,
+        plan,
+        current,
+        desired,
+    } = match prepare(service, users, options).await {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            error!("{}: {:#}", service.name(), e);
+            run.error = Some(format!("{e:#}"));
+            run.duration_seconds = timer.stop_and_record();
+            return run;
+        }
+    };
+
+    if !options.dry_run {
+        for action in &plan.actions {
+            let result = client.apply(action).await.map_err(|e| format!("{e:#}"));
+            match &result {
+                Ok(()) => {
+                    metrics::action_applied(service.name(), action);
+                    info!("{}: {}", service.name(), action);
+                }
+                Err(e) => {
+                    metrics::backend_error(service.name());
+                    error!("{}: {} failed: {}", service.name(), action, e);
+                }
+            }
+            if let (Some(history), Ok(())) = (&options.history, &result) {
+                if let Err(e) = history.record(service.name(), action) {
+                    error!("{}: failed to write the history: {:#}", service.name(), e);
+                }
+            }
+            if let Some(audit) = &options.audit {
+                let username = action.username();
+                let entry = AuditEntry {
+                    timestamp: Utc::now(),
+                    service: service.name(),
+                    username,
+                    action,
+                    before: current.iter().find(|account| account.username == username),
+                    after: desired.get(username),
+                    source: users.get(username),
+                    error: result.as_ref().err().map(String::as_str),
+                };
+                if let Err(e) = audit.record(&entry) {
+                    error!("{}: failed to write the audit log: {:#}", service.name(), e);
+                }
+            }
+            if let Err(error) = result {
+                run.failed.push(FailedAction {
+                    action: action.clone(),
+                    error,
+                });
+            }
+        }
+        if run.failed.is_empty() {
+            metrics::run_succeeded(service.name());
+        }
+    }
+    run.plan = plan;
+    run.duration_seconds = timer.stop_and_record();
+    run
+}
+
+/// A connected service with the plan to apply to it.
+struct Prepared<C> {
+    client: C,
+    plan: Plan,
+    /// Accounts in scope as fetched from the service.
+    current: Vec<Account>,
+    desired: BTreeMap<String, Account>,
+}
+
+/// `user` with its roles translated by the role mapping of `service`.
+fn mapped_user<S: Service + ?Sized>(service: &S, user: &UserConfig) -> UserConfig {
+    UserConfig {
+        roles: service
+            .role_mapping()
+            .map(user.role_names())
+            .into_iter()
+            .map(RoleAssignment::from)
+            .collect(),
+        ..user.clone()
+    }
+}
+
+/// Connects to `service`, computes and prints its plan and, unless this is a dry run,
+/// checks it against the safety brake.
+async fn prepare<S: Service + ?Sized>(
+    service: &S,
+    users: &HashMap<String, UserConfig>,
+    options: &RunOptions,
+) -> anyhow::Result<Prepared<S::Client>> {
+    let mut client = service
+        .connect()
+        .await
+        .inspect_err(|_| metrics::backend_error(service.name()))?;
+    let (protected, mut current): (Vec<_>, Vec<_>) = client
+        .fetch_accounts()
+        .await
+        .inspect_err(|_| metrics::backend_error(service.name()))?
+        .into_iter()
+        .partition(|account| is_protected(service, account));
+    let total_accounts = current.len();
+    current.retain(|account| options.scope.includes_user(&account.username));
+    for account in &protected {
+        info!(
+            "{}: {} is protected, skipping",
+            service.name(),
+            account.username
+        );
+    }
+    let mut desired = users
+        .iter()
+        .filter(|(username, _)| {
+            options.scope.includes_user(username)
+                && !matches_any(service.protected_users(), username)
+                && !protected
+                    .iter()
+                    .any(|account| account.username == **username)
+        })
+        .filter_map(|(username, user)| {
+            client
+                .desired_account(username, &mapped_user(service, user))
+                .map(|account| (username.clone(), account))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let missing = desired
+        .keys()
+        .filter(|username| !current.iter().any(|account| &account.username == *username))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    for username in client
+        .uncreatable(&missing)
+        .await
+        .inspect_err(|_| metrics::backend_error(service.name()))?
+    {
+        warn!(
+            "{}: {} has no account that can be added, skipping",
+            service.name(),
+            username
+        );
+        desired.remove(&username);
+    }
+
+    let plan = Plan::compute(service.name(), &desired, &current, service.on_removed());
+    if plan.is_empty() {
+        info!("{}: nothing to do", service.name());
+    } else {
+        options.print(&plan);
+    }
+
+    if !options.dry_run && !options.force {
+        plan.check_safety_brake(&options.safety_brake, total_accounts)?;
+    }
+    Ok(Prepared {
+        client,
+        plan,
+        current,
+        desired,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::pattern::Pattern;
+    use crate::role_mapping::RoleMapping;
+
+    fn account(username: &str, enabled: bool, roles: &[&str]) -> Account {
+        Account {
+            username: username.to_string(),
+            fields: BTreeMap::new(),
+            enabled,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn desired(accounts: &[Account]) -> BTreeMap<String, Account> {
+        accounts
+            .iter()
+            .map(|account| (account.username.clone(), account.clone()))
+            .collect()
+    }
+
+    fn compute(desired_accounts: &[Account], current: &[Account]) -> Vec<Action> {
+        Plan::compute(
+            "test",
+            &desired(desired_accounts),
+            current,
+            OffboardingPolicy::Delete,
+        )
+        .actions
+    }
+
+    #[test]
+    fn unchanged_accounts_need_no_actions() {
+        assert!(compute(
+            &[account("alice", true, &["admin"])],
+            &[account("alice", true, &["admin"])]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn creates_missing_accounts_with_their_roles() {
+        let mut alice = account("alice", true, &["admin", "user"]);
+        alice
+            .fields
+            .insert("email".to_string(), json!("alice@example.org"));
+        assert_eq!(
+            compute(&[alice.clone()], &[]),
+            vec![Action::CreateUser {
+                username: "alice".to_string(),
+                fields: alice.fields,
+                enabled: true,
+                roles: alice.roles,
+            }]
+        );
+    }
+
+    #[test]
+    fn updates_only_changed_fields() {
+        let mut current = account("alice", true, &[]);
+        current
+            .fields
+            .insert("email".to_string(), json!("old@example.org"));
+        current
+            .fields
+            .insert("firstName".to_string(), json!("Alice"));
+        current
+            .fields
+            .insert("lastName".to_string(), json!("Liddell"));
+        let mut wanted = account("alice", true, &[]);
+        wanted
+            .fields
+            .insert("email".to_string(), json!("new@example.org"));
+        wanted
+            .fields
+            .insert("firstName".to_string(), json!("Alice"));
+        wanted.fields.insert("locale".to_string(), json!("de"));
+        assert_eq!(
+            compute(&[wanted], &[current]),
+            vec![Action::UpdateUser {
+                username: "alice".to_string(),
+                changes: vec![
+                    FieldChange {
+                        field: "email".to_string(),
+                        from: Some(json!("old@example.org")),
+                        to: json!("new@example.org"),
+                    },
+                    FieldChange {
+                        field: "locale".to_string(),
+                        from: None,
+                        to: json!("de"),
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn grants_and_revokes_roles() {
+        assert_eq!(
+            compute(
+                &[account("alice", true, &["admin", "user"])],
+                &[account("alice", true, &["guest", "user"])],
+            ),
+            vec![
+                Action::GrantRole {
+                    username: "alice".to_string(),
+                    role: "admin".to_string(),
+                },
+                Action::RevokeRole {
+                    username: "alice".to_string(),
+                    role: "guest".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn enables_and_disables_existing_accounts() {
+        assert_eq!(
+            compute(
+                &[account("alice", true, &[]), account("bob", false, &[])],
+                &[account("alice", false, &[]), account("bob", true, &[])],
+            ),
+            vec![
+                Action::EnableUser {
+                    username: "alice".to_string(),
+                },
+                Action::DisableUser {
+                    username: "bob".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_actions_by_kind_then_username() {
+        let actions = compute(
+            &[
+                account("bob", true, &[]),
+                account("carol", true, &["admin"]),
+            ],
+            &[account("alice", true, &[]), account("carol", true, &[])],
+        );
+        assert_eq!(
+            actions,
+            vec![
+                Action::CreateUser {
+                    username: "bob".to_string(),
+                    fields: BTreeMap::new(),
+                    enabled: true,
+                    roles: BTreeSet::new(),
+                },
+                Action::GrantRole {
+                    username: "carol".to_string(),
+                    role: "admin".to_string(),
+                },
+                Action::DeleteUser {
+                    username: "alice".to_string(),
+                },
+            ]
+        );
+    }
+
+    fn offboard(policy: OffboardingPolicy) -> Plan {
+        Plan::compute(
+            "test",
+            &BTreeMap::new(),
+            &[account("alice", true, &[]), account("bob", false, &[])],
+            policy,
+        )
+    }
+
+    #[test]
+    fn delete_policy_deletes_removed_accounts() {
+        let plan = offboard(OffboardingPolicy::Delete);
+        assert_eq!(
+            plan.actions,
+            vec![
+                Action::DeleteUser {
+                    username: "alice".to_string(),
+                },
+                Action::DeleteUser {
+                    username: "bob".to_string(),
+                },
+            ]
+        );
+        assert_eq!(plan.removals(), 2);
+    }
+
+    #[test]
+    fn disable_policy_disables_removed_accounts_that_are_enabled() {
+        let plan = offboard(OffboardingPolicy::Disable);
+        assert_eq!(
+            plan.actions,
+            vec![Action::DisableUser {
+                username: "alice".to_string(),
+            }]
+        );
+        assert_eq!(plan.removals(), 1);
+    }
+
+    #[test]
+    fn ignore_policy_keeps_removed_accounts() {
+        assert!(offboard(OffboardingPolicy::Ignore).is_empty());
+    }
+
+    struct TestService {
+        protected_users: Vec<Pattern>,
+        protected_roles: Vec<Pattern>,
+        role_mapping: RoleMapping,
+        accounts: Vec<Account>,
+    }
+
+    struct TestClient {
+        accounts: Vec<Account>,
+    }
+
+    impl Service for TestService {
+        type Client = TestClient;
+
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn on_removed(&self) -> OffboardingPolicy {
+            OffboardingPolicy::Delete
+        }
+
+        fn protected_users(&self) -> &[Pattern] {
+            &self.protected_users
+        }
+
+        fn protected_roles(&self) -> &[Pattern] {
+            &self.protected_roles
+        }
+
+        fn role_mapping(&self) -> &RoleMapping {
+            &self.role_mapping
+        }
+
+        async fn connect(&self) -> anyhow::Result<TestClient> {
+            Ok(TestClient {
+                accounts: self.accounts.clone(),
+            })
+        }
+    }
+
+    impl ServiceClient for TestClient {
+        async fn fetch_accounts(&mut self) -> anyhow::Result<Vec<Account>> {
+            Ok(self.accounts.clone())
+        }
+
+        fn desired_account(&self, username: &str, user: &UserConfig) -> Option<Account> {
+            Some(account(
+                username,
+                user.enabled,
+                &user.role_names().collect::<Vec<_>>(),
+            ))
+        }
+
+        async fn apply(&mut self, _action: &Action) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn protecting_service(accounts: Vec<Account>) -> TestService {
+        TestService {
+            protected_users: serde_json::from_value(json!(["admin", "break-glass-*"])).unwrap(),
+            protected_roles: serde_json::from_value(json!([{"regex": ".*-admin"}])).unwrap(),
+            role_mapping: RoleMapping::default(),
+            accounts,
+        }
+    }
+
+    #[test]
+    fn protects_by_username_and_role() {
+        let service = protecting_service(Vec::new());
+        assert!(is_protected(&service, &account("admin", true, &[])));
+        assert!(is_protected(&service, &account("break-glass-1", true, &[])));
+        assert!(is_protected(
+            &service,
+            &account("alice", true, &["realm-admin"])
+        ));
+        assert!(!is_protected(
+            &service,
+            &account("administrator", true, &[])
+        ));
+        assert!(!is_protected(&service, &account("alice", true, &["admin"])));
+    }
+
+    #[tokio::test]
+    async fn prepare_leaves_protected_accounts_out() {
+        let service = protecting_service(vec![
+            account("admin", true, &[]),
+            account("carol", true, &["realm-admin"]),
+            account("dave", true, &[]),
+        ]);
+        // admin and carol exist but are protected, break-glass-1 would be created and dave
+        // deleted if they were not
+        let users = serde_json::from_value::<HashMap<String, UserConfig>>(json!({
+            "admin": {"roles": ["users"]},
+            "carol": {"roles": ["users"]},
+            "break-glass-1": {"roles": ["users"]},
+            "alice": {"roles": ["users"]}
+        }))
+        .unwrap();
+        let options = RunOptions {
+            dry_run: true,
+            ..RunOptions::default()
+        };
+
+        let prepared = prepare(&service, &users, &options).await.unwrap();
+
+        assert_eq!(prepared.desired.keys().collect::<Vec<_>>(), vec!["alice"]);
+        assert_eq!(prepared.current, vec![account("dave", true, &[])]);
+        assert_eq!(
+            prepared.plan.actions,
+            vec![
+                Action::CreateUser {
+                    username: "alice".to_string(),
+                    fields: BTreeMap::new(),
+                    enabled: true,
+                    roles: BTreeSet::from(["users".to_string()]),
+                },
+                Action::DeleteUser {
+                    username: "dave".to_string(),
+                },
+            ]
+        );
+    }
+}
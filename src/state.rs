@@ -0,0 +1,252 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::services::plan::{percent, SafetyBrake};
+use crate::UserConfig;
+
+/// Settings of the snapshot of the last applied user set.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    /// JSON file the snapshot is kept in.
+    pub path: PathBuf,
+}
+
+/// The user set of the last run that was applied to every service without errors.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Snapshot<'a> {
+    pub saved_at: DateTime<Utc>,
+    pub users: Cow<'a, HashMap<String, UserConfig>>,
+}
+
+impl Snapshot<'_> {
+    /// Loads the snapshot at `path`, `None` if there is none yet.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Snapshot<'static>>> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the snapshot atomically, so an interrupted run cannot leave a broken file.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// What changed in the user source since the snapshot.
+#[derive(Debug, Default)]
+pub struct SourceDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<UserChange>,
+}
+
+#[derive(Debug)]
+pub struct UserChange {
+    pub username: String,
+    pub granted: Vec<String>,
+    pub revoked: Vec<String>,
+    /// Names of other changed fields, e.g. `email`.
+    pub fields: Vec<&'static str>,
+}
+
+impl SourceDiff {
+    pub fn compute(
+        previous: &HashMap<String, UserConfig>,
+        current: &HashMap<String, UserConfig>,
+    ) -> Self {
+        let mut diff = SourceDiff::default();
+        for (username, user) in current {
+            let Some(old) = previous.get(username) else {
+                diff.added.push(username.clone());
+                continue;
+            };
+            let old_roles = old.role_names().collect::<BTreeSet<_>>();
+            let roles = user.role_names().collect::<BTreeSet<_>>();
+            let fields = [
+                ("first_name", old.first_name != user.first_name),
+                ("last_name", old.last_name != user.last_name),
+                ("email", old.email != user.email),
+                ("matrix_id", old.matrix_id != user.matrix_id),
+                ("enabled", old.enabled != user.enabled),
+            ]
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(field, _)| field)
+            .collect::<Vec<_>>();
+            if old_roles != roles || !fields.is_empty() {
+                diff.changed.push(UserChange {
+                    username: username.clone(),
+                    granted: roles
+                        .difference(&old_roles)
+                        .map(|r| r.to_string())
+                        .collect(),
+                    revoked: old_roles
+                        .difference(&roles)
+                        .map(|r| r.to_string())
+                        .collect(),
+                    fields,
+                });
+            }
+        }
+        diff.removed = previous
+            .keys()
+            .filter(|username| !current.contains_key(*username))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort_by(|a, b| a.username.cmp(&b.username));
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Users that were removed or lost all their roles, as a broken source, e.g. a
+    /// renamed column, typically drops them all at once. Users whose roles all ended with
+    /// their term on `today` are expected to lose them, e.g. at the end of a Wahlperiode,
+    /// and are not counted.
+    fn losses(
+        &self,
+        previous: &HashMap<String, UserConfig>,
+        current: &HashMap<String, UserConfig>,
+        today: NaiveDate,
+    ) -> usize {
+        self.removed.len()
+            + self
+                .changed
+                .iter()
+                .filter(|change| {
+                    current[&change.username].roles.is_empty()
+                        && change.revoked.iter().any(|role| {
+                            previous[&change.username].roles.iter().any(|assignment| {
+                                assignment.role == *role && assignment.is_active(today)
+                            })
+                        })
+                })
+                .count()
+    }
+
+    /// Fails if the source lost more users on `today` than `brake` allows since the
+    /// snapshot.
+    pub fn check_safety_brake(
+        &self,
+        brake: &SafetyBrake,
+        previous: &HashMap<String, UserConfig>,
+        current: &HashMap<String, UserConfig>,
+        today: NaiveDate,
+    ) -> anyhow::Result<()> {
+        let losses = self.losses(previous, current, today);
+        if !brake.allows(losses, previous.len()) {
+            anyhow::bail!(
+                "the user source lost {} of {} users ({:.0}%) since the last applied run, the limit is {} users or {}%; check the source or rerun with --force if this is intended",
+                losses,
+                previous.len(),
+                percent(losses, previous.len()),
+                brake.max_removals,
+                brake.max_removal_percent
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SourceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for username in &self.added {
+            writeln!(f, "[source] + new user {username}")?;
+        }
+        for change in &self.changed {
+            write!(f, "[source] ~ {}:", change.username)?;
+            for role in &change.granted {
+                write!(f, " +{role}")?;
+            }
+            for role in &change.revoked {
+                write!(f, " -{role}")?;
+            }
+            for field in &change.fields {
+                write!(f, " {field} changed")?;
+            }
+            writeln!(f)?;
+        }
+        for username in &self.removed {
+            writeln!(f, "[source] - removed user {username}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn users(users: serde_json::Value) -> HashMap<String, UserConfig> {
+        serde_json::from_value(users).unwrap()
+    }
+
+    fn brake() -> SafetyBrake {
+        SafetyBrake {
+            max_removals: 1,
+            max_removal_percent: 100.0,
+        }
+    }
+
+    #[test]
+    fn roles_ending_with_their_term_are_no_loss() {
+        let term = json!([{"role": "FS_Rat", "valid_until": "2025-09-30"}]);
+        let previous = users(json!({
+            "alice": {"roles": term},
+            "bob": {"roles": term},
+            "carol": {"roles": term}
+        }));
+        let current = users(json!({
+            "alice": {"roles": []},
+            "bob": {"roles": []},
+            "carol": {"roles": []}
+        }));
+        let diff = SourceDiff::compute(&previous, &current);
+        let after_term = NaiveDate::from_ymd_opt(2025, 10, 1).unwrap();
+        assert_eq!(diff.losses(&previous, &current, after_term), 0);
+        assert!(diff
+            .check_safety_brake(&brake(), &previous, &current, after_term)
+            .is_ok());
+
+        let during_term = NaiveDate::from_ymd_opt(2025, 9, 1).unwrap();
+        assert_eq!(diff.losses(&previous, &current, during_term), 3);
+        assert!(diff
+            .check_safety_brake(&brake(), &previous, &current, during_term)
+            .is_err());
+    }
+
+    #[test]
+    fn removed_users_and_lost_indefinite_roles_are_losses() {
+        let previous = users(json!({
+            "alice": {"roles": ["Vorstand"]},
+            "bob": {"roles": ["Vorstand"]},
+            "carol": {"roles": ["Vorstand", "Kasse"]}
+        }));
+        let current = users(json!({
+            "alice": {"roles": []},
+            "carol": {"roles": ["Kasse"]}
+        }));
+        let diff = SourceDiff::compute(&previous, &current);
+        let today = NaiveDate::from_ymd_opt(2025, 10, 1).unwrap();
+        assert_eq!(diff.losses(&previous, &current, today), 2);
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{Error, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+/// Deserializes a configuration value that is either a plain string or a map. Unlike an
+/// untagged enum, an unknown or misspelt key in the map is reported by name, because the
+/// map is deserialized as `M` directly instead of being tried against every variant.
+pub fn deserialize<'de, D, M, T>(
+    deserializer: D,
+    expecting: &'static str,
+    string: fn(String) -> T,
+    map: fn(M) -> T,
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    M: Deserialize<'de>,
+{
+    struct StringOrMapVisitor<M, T> {
+        expecting: &'static str,
+        string: fn(String) -> T,
+        map: fn(M) -> T,
+        marker: PhantomData<M>,
+    }
+
+    impl<'de, M: Deserialize<'de>, T> Visitor<'de> for StringOrMapVisitor<M, T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.expecting)
+        }
+
+        fn visit_str<E: Error>(self, value: &str) -> Result<T, E> {
+            Ok((self.string)(value.to_string()))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+            M::deserialize(MapAccessDeserializer::new(map)).map(self.map)
+        }
+    }
+
+    deserializer.deserialize_any(StringOrMapVisitor {
+        expecting,
+        string,
+        map,
+        marker: PhantomData,
+    })
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::one_or_many::OneOrMany;
+use crate::{UserConfig, UserConfigProvider};
+
+/// One user source, or a list of them merged into one user set. Earlier sources take
+/// precedence over later ones.
+pub type UserSources = OneOrMany<UserConfigProvider>;
+
+/// How the roles of a user found in several sources are combined.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RoleMerge {
+    /// The user holds the roles from every source.
+    #[default]
+    Union,
+    /// Only the roles from the source with the highest precedence count.
+    Override,
+}
+
+/// A field of a user that has different values in two sources.
+#[derive(Debug)]
+pub struct Conflict {
+    pub username: String,
+    pub field: &'static str,
+    /// Value and name of the source with the higher precedence, which is used.
+    pub kept: (String, String),
+    /// Value and name of the source whose value is ignored.
+    pub ignored: (String, String),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} is {} in {}, but {} in {}",
+            self.username, self.field, self.kept.0, self.kept.1, self.ignored.0, self.ignored.1
+        )
+    }
+}
+
+/// Merges the users of `sources`, given as source name and users in the order of their
+/// precedence. Fields missing in one source are taken from the next one that has them.
+/// A user disabled in any source is disabled, regardless of the precedence.
+pub fn merge(
+    sources: Vec<(String, HashMap<String, UserConfig>)>,
+    roles: RoleMerge,
+) -> (HashMap<String, UserConfig>, Vec<Conflict>) {
+    let mut merged = HashMap::<String, (UserConfig, &str)>::new();
+    let mut conflicts = Vec::new();
+    for (source, users) in &sources {
+        for (username, user) in users {
+            let Some((existing, existing_source)) = merged.get_mut(username) else {
+                merged.insert(username.clone(), (user.clone(), source));
+                continue;
+            };
+            for (field, kept, other) in [
+                ("first_name", &mut existing.first_name, &user.first_name),
+                ("last_name", &mut existing.last_name, &user.last_name),
+                ("email", &mut existing.email, &user.email),
+                ("matrix_id", &mut existing.matrix_id, &user.matrix_id),
+            ] {
+                match (kept.as_ref(), other) {
+                    (None, _) => kept.clone_from(other),
+                    (Some(kept), Some(other)) if kept != other => conflicts.push(Conflict {
+                        username: username.clone(),
+                        field,
+                        kept: (kept.clone(), existing_source.to_string()),
+                        ignored: (other.clone(), source.clone()),
+                    }),
+                    _ => {}
+                }
+            }
+            if existing.enabled != user.enabled {
+                let (disabled, enabled) = if existing.enabled {
+                    (source.as_str(), *existing_source)
+                } else {
+                    (*existing_source, source.as_str())
+                };
+                conflicts.push(Conflict {
+                    username: username.clone(),
+                    field: "enabled",
+                    kept: ("false".to_string(), disabled.to_string()),
+                    ignored: ("true".to_string(), enabled.to_string()),
+                });
+                existing.enabled = false;
+            }
+            if let RoleMerge::Union = roles {
+                existing.roles.extend(user.roles.iter().cloned());
+            }
+        }
+    }
+    conflicts.sort_by(|a, b| (&a.username, a.field).cmp(&(&b.username, b.field)));
+    let users = merged
+        .into_iter()
+        .map(|(username, (user, _))| (username, user))
+        .collect();
+    (users, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn source(name: &str, users: serde_json::Value) -> (String, HashMap<String, UserConfig>) {
+        (name.to_string(), serde_json::from_value(users).unwrap())
+    }
+
+    fn sources() -> Vec<(String, HashMap<String, UserConfig>)> {
+        vec![
+            source(
+                "table",
+                json!({
+                    "alice": {"first_name": "Alice", "email": "alice@hhu.de", "roles": ["Rat"]},
+                    "bob": {"roles": ["Kasse"]}
+                }),
+            ),
+            source(
+                "file",
+                json!({
+                    "alice": {
+                        "first_name": "Alice",
+                        "last_name": "Liddell",
+                        "email": "alice@example.org",
+                        "roles": ["Admin"]
+                    },
+                    "carol": {"roles": ["Technik"]}
+                }),
+            ),
+        ]
+    }
+
+    fn roles(user: &UserConfig) -> Vec<&str> {
+        user.role_names().collect()
+    }
+
+    #[test]
+    fn earlier_sources_take_precedence_and_missing_fields_are_filled() {
+        let (users, _) = merge(sources(), RoleMerge::Union);
+        assert_eq!(users.len(), 3);
+        let alice = &users["alice"];
+        assert_eq!(alice.email.as_deref(), Some("alice@hhu.de"));
+        assert_eq!(alice.last_name.as_deref(), Some("Liddell"));
+        assert_eq!(roles(&users["carol"]), vec!["Technik"]);
+    }
+
+    #[test]
+    fn union_combines_the_roles_and_override_keeps_the_first() {
+        let (users, _) = merge(sources(), RoleMerge::Union);
+        assert_eq!(roles(&users["alice"]), vec!["Rat", "Admin"]);
+        let (users, _) = merge(sources(), RoleMerge::Override);
+        assert_eq!(roles(&users["alice"]), vec!["Rat"]);
+    }
+
+    #[test]
+    fn reports_different_values_as_conflicts() {
+        let (_, conflicts) = merge(sources(), RoleMerge::Union);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0].to_string(),
+            "alice: email is alice@hhu.de in table, but alice@example.org in file"
+        );
+    }
+
+    #[test]
+    fn disabled_wins_and_is_reported() {
+        for (first, second) in [(true, false), (false, true)] {
+            let (users, conflicts) = merge(
+                vec![
+                    source("csv", json!({"alice": {"roles": [], "enabled": first}})),
+                    source("file", json!({"alice": {"roles": [], "enabled": second}})),
+                ],
+                RoleMerge::Union,
+            );
+            assert!(!users["alice"].enabled);
+            let disabled_in = if first { "file" } else { "csv" };
+            let enabled_in = if first { "csv" } else { "file" };
+            assert_eq!(
+                conflicts[0].to_string(),
+                format!("alice: enabled is false in {disabled_in}, but true in {enabled_in}")
+            );
+        }
+    }
+}